@@ -5,7 +5,9 @@ use core::{
 
 use crate::locked_mutex::Locked;
 
-const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+use super::grow_heap;
+
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
 fn list_index(layout: &Layout) -> Option<usize> {
     // Block size must be at least the size of the alignment
     let required_block_size = layout.size().max(layout.align());
@@ -15,21 +17,75 @@ fn list_index(layout: &Layout) -> Option<usize> {
         .position(|&size| size >= required_block_size)
 }
 
+// Freed memory is filled with this when the heap-debug feature is on
+#[cfg(feature = "heap-debug")]
+const POISON: u8 = 0xDE;
+
 struct ListNode {
     next: Option<&'static mut ListNode>,
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeClassStats {
+    pub block_size: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub live_blocks: usize,
+    // Freed blocks sitting in the list waiting to be reused
+    pub cached_blocks: usize,
+}
+
+impl SizeClassStats {
+    const fn empty() -> Self {
+        Self {
+            block_size: 0,
+            allocs: 0,
+            frees: 0,
+            live_blocks: 0,
+            cached_blocks: 0,
+        }
+    }
+
+    pub fn live_bytes(&self) -> usize {
+        self.live_blocks * self.block_size
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    // Allocations too big for a size class
+    pub large_live_blocks: usize,
+    pub large_live_bytes: usize,
+    // Usage of the linked list allocator, including blocks cached by the size classes
+    pub fallback_used: usize,
+    pub fallback_free: usize,
+    pub fallback_size: usize,
+    // Bytes handed out that haven't been freed
+    pub live_bytes: usize,
+    pub high_water_mark: usize,
+}
+
 pub struct FixedSizeBlockAllocator {
     list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
     fallback_allocator: linked_list_allocator::Heap,
+    size_classes: [SizeClassStats; BLOCK_SIZES.len()],
+    large_live_blocks: usize,
+    large_live_bytes: usize,
+    high_water_mark: usize,
 }
 
 impl FixedSizeBlockAllocator {
     pub const fn new() -> Self {
         const EMPTY: Option<&'static mut ListNode> = None;
+        const EMPTY_STATS: SizeClassStats = SizeClassStats::empty();
         Self {
             list_heads: [EMPTY; BLOCK_SIZES.len()],
             fallback_allocator: linked_list_allocator::Heap::empty(),
+            size_classes: [EMPTY_STATS; BLOCK_SIZES.len()],
+            large_live_blocks: 0,
+            large_live_bytes: 0,
+            high_water_mark: 0,
         }
     }
 
@@ -41,22 +97,95 @@ impl FixedSizeBlockAllocator {
         self.fallback_allocator.init(heap_start, heap_size)
     }
 
+    pub fn stats(&self) -> HeapStats {
+        let mut size_classes = self.size_classes;
+        for (stats, &block_size) in size_classes.iter_mut().zip(BLOCK_SIZES) {
+            stats.block_size = block_size;
+        }
+
+        HeapStats {
+            size_classes,
+            large_live_blocks: self.large_live_blocks,
+            large_live_bytes: self.large_live_bytes,
+            fallback_used: self.fallback_allocator.used(),
+            fallback_free: self.fallback_allocator.free(),
+            fallback_size: self.fallback_allocator.size(),
+            live_bytes: self.live_bytes(),
+            high_water_mark: self.high_water_mark,
+        }
+    }
+
+    fn live_bytes(&self) -> usize {
+        let class_bytes: usize = self
+            .size_classes
+            .iter()
+            .zip(BLOCK_SIZES)
+            .map(|(stats, &block_size)| stats.live_blocks * block_size)
+            .sum();
+        class_bytes + self.large_live_bytes
+    }
+
     fn fallboack_alloc(&mut self, layout: Layout) -> *mut u8 {
-        match self.fallback_allocator.allocate_first_fit(layout) {
-            Ok(ptr) => ptr.as_ptr(),
-            Err(_) => core::ptr::null_mut(),
+        if let Ok(ptr) = self.fallback_allocator.allocate_first_fit(layout) {
+            return ptr.as_ptr();
+        }
+
+        // Out of heap, map some more and try again
+        // Ask for enough that the allocation fits even after aligning
+        match grow_heap(layout.size() + layout.align()) {
+            Some(grown) => {
+                unsafe { self.fallback_allocator.extend(grown) };
+                match self.fallback_allocator.allocate_first_fit(layout) {
+                    Ok(ptr) => ptr.as_ptr(),
+                    Err(_) => core::ptr::null_mut(),
+                }
+            }
+            None => core::ptr::null_mut(),
+        }
+    }
+
+    // Whether ptr is already in the free list, which means it's being freed twice
+    #[cfg(feature = "heap-debug")]
+    fn is_free(&self, index: usize, ptr: *mut u8) -> bool {
+        let mut node = &self.list_heads[index];
+        while let Some(current) = node {
+            if &**current as *const ListNode as *const u8 == ptr {
+                return true;
+            }
+            node = &current.next;
+        }
+        false
+    }
+
+    // Checks a block that is about to be reused hasn't been written to since it was freed
+    #[cfg(feature = "heap-debug")]
+    unsafe fn check_poison(ptr: *mut u8, block_size: usize) {
+        let start = core::mem::size_of::<ListNode>();
+        let block = core::slice::from_raw_parts(ptr, block_size);
+        if block[start..].iter().any(|&byte| byte != POISON) {
+            println!(
+                "WARNING: heap block {:p} ({} bytes) was written to after being freed",
+                ptr, block_size
+            );
         }
     }
 }
+
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
-        match list_index(&layout) {
+        let ptr = match list_index(&layout) {
             Some(index) => {
-                match allocator.list_heads[index].take() {
+                let ptr = match allocator.list_heads[index].take() {
                     Some(node) => {
                         allocator.list_heads[index] = node.next.take();
-                        node as *mut ListNode as *mut u8
+                        allocator.size_classes[index].cached_blocks -= 1;
+                        let ptr = node as *mut ListNode as *mut u8;
+
+                        #[cfg(feature = "heap-debug")]
+                        FixedSizeBlockAllocator::check_poison(ptr, BLOCK_SIZES[index]);
+
+                        ptr
                     }
                     None => {
                         // No block exists in list => allocate new block
@@ -66,16 +195,42 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                         let layout = Layout::from_size_align(block_size, block_align).unwrap();
                         allocator.fallboack_alloc(layout)
                     }
+                };
+
+                if !ptr.is_null() {
+                    allocator.size_classes[index].allocs += 1;
+                    allocator.size_classes[index].live_blocks += 1;
                 }
+                ptr
             }
-            None => allocator.fallboack_alloc(layout),
-        }
+            None => {
+                let ptr = allocator.fallboack_alloc(layout);
+                if !ptr.is_null() {
+                    allocator.large_live_blocks += 1;
+                    allocator.large_live_bytes += layout.size();
+                }
+                ptr
+            }
+        };
+
+        allocator.high_water_mark = allocator.high_water_mark.max(allocator.live_bytes());
+        ptr
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
         match list_index(&layout) {
             Some(index) => {
+                #[cfg(feature = "heap-debug")]
+                {
+                    if allocator.is_free(index, ptr) {
+                        // A panicking thread is only ended, so the heap mustn't be left locked
+                        drop(allocator);
+                        panic!("Double free of {:p} ({:?})", ptr, layout);
+                    }
+                    ptr.write_bytes(POISON, BLOCK_SIZES[index]);
+                }
+
                 // Return block to correct list size
                 let new_node = ListNode {
                     next: allocator.list_heads[index].take(),
@@ -87,11 +242,24 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
                 let new_node_ptr = ptr as *mut ListNode;
                 new_node_ptr.write(new_node);
                 allocator.list_heads[index] = Some(&mut *new_node_ptr);
+
+                let stats = &mut allocator.size_classes[index];
+                stats.frees += 1;
+                stats.live_blocks = stats.live_blocks.saturating_sub(1);
+                stats.cached_blocks += 1;
             }
             None => {
+                // Large blocks aren't tracked so only poison them
+                // A double free of one goes straight to the fallback allocator and isn't caught
+                #[cfg(feature = "heap-debug")]
+                ptr.write_bytes(POISON, layout.size());
+
                 // Just dealloc it
                 let pointer = NonNull::new(ptr).unwrap();
                 allocator.fallback_allocator.deallocate(pointer, layout);
+
+                allocator.large_live_blocks = allocator.large_live_blocks.saturating_sub(1);
+                allocator.large_live_bytes = allocator.large_live_bytes.saturating_sub(layout.size());
             }
         }
     }
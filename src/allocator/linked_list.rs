@@ -1,6 +1,6 @@
 use core::{
     alloc::{self, GlobalAlloc},
-    mem,
+    mem, ptr,
 };
 
 use crate::{allocator::align_up, locked_mutex::Locked};
@@ -46,22 +46,74 @@ impl LinkedListAllocator {
         self.add_free_region(heap_start, heap_size);
     }
 
-    // Adds the new memory location to the front of the list
+    // Adds the memory region to the list, keeping the list sorted by address
+    // Regions that touch their neighbours are merged into a single region
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // Ensure that the freed region is capable of holding a ListNode
         assert_eq!(align_up(addr, core::mem::align_of::<ListNode>()), addr);
         assert!(size >= core::mem::size_of::<ListNode>());
 
+        // Find the last region that starts before this one
+        let mut previous_region = &mut self.head;
+        loop {
+            match previous_region.next {
+                Some(ref next) if next.start_addr() < addr => {}
+                _ => break,
+            }
+            previous_region = previous_region.next.as_mut().unwrap();
+        }
+
+        // The head is the only node with a size of 0
+        if previous_region.size != 0 && previous_region.end_addr() == addr {
+            // Grow the previous region to cover this one
+            previous_region.size += size;
+            Self::merge_with_next(previous_region);
+            return;
+        }
+
         // Create a new list node
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        node.next = previous_region.next.take();
 
         // Write the list node to memory
         let node_ptr = addr as *mut ListNode;
         node_ptr.write(node);
 
-        // Write the location to the next free mem location
-        self.head.next = Some(&mut *node_ptr)
+        // Link it in after the previous region
+        previous_region.next = Some(&mut *node_ptr);
+        Self::merge_with_next(previous_region.next.as_mut().unwrap());
+    }
+
+    // If the region after this one starts where this one ends combine them
+    fn merge_with_next(region: &mut ListNode) {
+        let touching = match region.next {
+            Some(ref next) => region.end_addr() == next.start_addr(),
+            None => false,
+        };
+
+        if touching {
+            let next = region.next.take().unwrap();
+            region.size += next.size;
+            region.next = next.next.take();
+        }
+    }
+
+    // Removes the free region starting at exactly addr from the list
+    fn take_region_at(&mut self, addr: usize) -> Option<&'static mut ListNode> {
+        let mut previous_region = &mut self.head;
+        loop {
+            match previous_region.next {
+                Some(ref next) if next.start_addr() < addr => {}
+                Some(ref next) if next.start_addr() == addr => {
+                    let region = previous_region.next.take().unwrap();
+                    previous_region.next = region.next.take();
+                    return Some(region);
+                }
+                // Sorted by address so it can't be further along
+                _ => return None,
+            }
+            previous_region = previous_region.next.as_mut().unwrap();
+        }
     }
 
     // Looks for a free regoin with the givin size and alignment and takes it
@@ -91,7 +143,13 @@ impl LinkedListAllocator {
     }
 
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        if alloc_start != region.start_addr()
+            && alloc_start - region.start_addr() < mem::size_of::<ListNode>()
+        {
+            // The gap before the allocation must be able to hold a ListNode too
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -130,9 +188,16 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
         if let Some((region, alloc_start)) = allocator.find_region(size, align) {
             // End of allocated memory
             let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let region_start = region.start_addr();
+            let region_end = region.end_addr();
+
+            // If aligning left a gap at the start, give it back
+            if alloc_start > region_start {
+                allocator.add_free_region(region_start, alloc_start - region_start);
+            }
 
             // How much excess memory?
-            let excess_size = region.end_addr() - alloc_end;
+            let excess_size = region_end - alloc_end;
             // If excess memory, add it the the list
             if excess_size > 0 {
                 allocator.add_free_region(alloc_end, excess_size);
@@ -140,7 +205,7 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
 
             alloc_start as *mut u8
         } else {
-            core::ptr::null_mut()
+            ptr::null_mut()
         }
     }
 
@@ -150,4 +215,50 @@ unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
 
         self.lock().add_free_region(ptr as usize, size)
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: alloc::Layout, new_size: usize) -> *mut u8 {
+        let new_layout = alloc::Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_size, _) = LinkedListAllocator::size_align(layout);
+        let (new_size, _) = LinkedListAllocator::size_align(new_layout);
+
+        {
+            let mut allocator = self.lock();
+
+            if new_size == old_size {
+                return ptr;
+            } else if new_size < old_size {
+                // Shrink in place if the end can be freed as its own region
+                let excess_size = old_size - new_size;
+                if excess_size >= mem::size_of::<ListNode>() {
+                    allocator.add_free_region(ptr as usize + new_size, excess_size);
+                    return ptr;
+                }
+            } else {
+                // Grow in place if the memory straight after us is free
+                let following = ptr as usize + old_size;
+                if let Some(region) = allocator.take_region_at(following) {
+                    let needed = new_size - old_size;
+                    let region_size = region.size;
+
+                    if region_size == needed {
+                        return ptr;
+                    } else if region_size >= needed + mem::size_of::<ListNode>() {
+                        allocator.add_free_region(following + needed, region_size - needed);
+                        return ptr;
+                    }
+
+                    // Not big enough, put it back
+                    allocator.add_free_region(following, region_size);
+                }
+            }
+        }
+
+        // Move the allocation somewhere else
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_layout.size()));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
 }
@@ -1,20 +1,72 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
+use crate::memory::{
+    huge_page,
+    layout::{self, Region},
+    with_paging,
+};
+
+#[cfg(feature = "alloc-locked-heap")]
+use linked_list_allocator::LockedHeap;
+
+#[cfg(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block"
+))]
 use crate::locked_mutex::Locked;
 
+#[cfg(feature = "alloc-bump")]
+use self::bump::BumpAllocator;
+#[cfg(feature = "alloc-fixed-block")]
 use self::fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(feature = "alloc-linked-list")]
+use self::linked_list::LinkedListAllocator;
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-block",
+    feature = "alloc-locked-heap"
+)))]
+compile_error!("No global allocator selected, enable one of the alloc-* features");
+
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-block"),
+    all(feature = "alloc-bump", feature = "alloc-locked-heap"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-locked-heap"),
+    all(feature = "alloc-fixed-block", feature = "alloc-locked-heap")
+))]
+compile_error!("Only one alloc-* feature can be enabled, try --no-default-features");
 
-pub const HEAP_START: usize = 0x4444_4444_0000;
 pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+// Default limit on how far the heap can grow
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+// Grow the heap by at least this much so we aren't mapping single pages
+const HEAP_GROW_SIZE: usize = 64 * 1024; // 64 KiB
+// Address space set aside for the heap, it can never grow past this
+const HEAP_RESERVED_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64 GiB
+// So the heap can be mapped with huge pages as it grows
+const HEAP_ALIGN: u64 = 2 * 1024 * 1024;
+
+// Where the heap starts, handed out by the address space layout
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+// How much of the heap is currently mapped
+static HEAP_MAPPED: AtomicUsize = AtomicUsize::new(0);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 pub mod bump;
 pub mod fixed_size_block;
 pub mod linked_list;
+pub mod slab;
 
 fn align_up(addr: usize, align: usize) -> usize {
     let remainder = addr % align;
@@ -25,46 +77,134 @@ fn align_up(addr: usize, align: usize) -> usize {
     }
 }
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            // Convert None to an error of FrameAllocationFailed
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+/// Maps fresh frames to every page in start..end
+/// Parts of the range that are aligned for it get huge pages
+fn map_heap_pages(start: usize, end: usize) -> Result<(), MapToError<Size4KiB>> {
+    let start = VirtAddr::new(start as u64).align_down(4096u64);
+    let end = VirtAddr::new(end as u64).align_up(4096u64);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    // Unmaps whatever it did map if it fails, so the range can be mapped again later
+    with_paging(|mapper, frame_allocator| {
+        huge_page::map_region(mapper, frame_allocator, start, end - start, flags)
+    })
+}
+
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let start = layout::allocate(Region::Heap, HEAP_RESERVED_SIZE as u64, HEAP_ALIGN)
+        .expect("No address space left for the heap")
+        .as_u64() as usize;
+    map_heap_pages(start, start + HEAP_SIZE)?;
+    HEAP_START.store(start, Ordering::SeqCst);
+    HEAP_MAPPED.store(HEAP_SIZE, Ordering::SeqCst);
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.lock().init(start, HEAP_SIZE);
     }
 
     Ok(())
 }
 
+/// Sets the size the heap is allowed to grow to
+/// It will never shrink below what is already mapped, or grow past the address space set aside for it
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit.min(HEAP_RESERVED_SIZE), Ordering::SeqCst);
+}
+
+/// Where the heap starts, 0 before init_heap
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::SeqCst)
+}
+
+/// Currently mapped size of the heap
+pub fn heap_size() -> usize {
+    HEAP_MAPPED.load(Ordering::SeqCst)
+}
+
+/// Maps at least min_size more bytes onto the end of the heap
+/// Returns how many bytes were added, or None if the limit was hit or memory ran out
+fn grow_heap(min_size: usize) -> Option<usize> {
+    let mapped = HEAP_MAPPED.load(Ordering::SeqCst);
+    let limit = HEAP_LIMIT.load(Ordering::SeqCst);
+
+    let grow_by = align_up(min_size.max(HEAP_GROW_SIZE), 4096).min(limit.saturating_sub(mapped));
+    if grow_by < min_size {
+        return None;
+    }
+
+    let heap_end = heap_start() + mapped;
+    if let Err(err) = map_heap_pages(heap_end, heap_end + grow_by) {
+        println!("WARNING: failed to grow heap: {:?}", err);
+        return None;
+    }
+
+    HEAP_MAPPED.store(mapped + grow_by, Ordering::SeqCst);
+    Some(grow_by)
+}
+
+/// Prints usage of each size class and the fallback allocator
+#[cfg(feature = "alloc-fixed-block")]
+pub fn print_heap_stats() {
+    let stats = ALLOCATOR.lock().stats();
+
+    println!(
+        "Heap: {} KiB mapped (limit {} KiB), {} bytes in use, high water mark {} bytes\n",
+        heap_size() / 1024,
+        HEAP_LIMIT.load(Ordering::SeqCst) / 1024,
+        stats.live_bytes,
+        stats.high_water_mark
+    );
+
+    println!(
+        "{:>6} {:>9} {:>9} {:>7} {:>10} {:>7}",
+        "Block", "Allocs", "Frees", "Live", "Live bytes", "Cached"
+    );
+    for class in stats.size_classes.iter() {
+        println!(
+            "{:>6} {:>9} {:>9} {:>7} {:>10} {:>7}",
+            class.block_size,
+            class.allocs,
+            class.frees,
+            class.live_blocks,
+            class.live_bytes(),
+            class.cached_blocks
+        );
+    }
+
+    println!(
+        "\nLarge allocations: {} live using {} bytes",
+        stats.large_live_blocks, stats.large_live_bytes
+    );
+    println!(
+        "Fallback allocator: {} used, {} free, {} total bytes",
+        stats.fallback_used, stats.fallback_free, stats.fallback_size
+    );
+}
+
+#[cfg(not(feature = "alloc-fixed-block"))]
+pub fn print_heap_stats() {
+    println!(
+        "Heap: {} KiB mapped\n\nDetailed statistics are only kept by the fixed size block allocator",
+        heap_size() / 1024
+    );
+}
+
 //* Use LockedHeap allocator crate
-// #[global_allocator]
-// static ALLOCATOR: LockedHeap = LockedHeap::empty();
+#[cfg(feature = "alloc-locked-heap")]
+#[global_allocator]
+pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 //* Use bump allocator
-// #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+#[cfg(feature = "alloc-bump")]
+#[global_allocator]
+pub static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
 
 //* Use Linked List
-// #[global_allocator]
-// static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+#[cfg(feature = "alloc-linked-list")]
+#[global_allocator]
+pub static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
 
 //* Use Fixed Block Sizes
+#[cfg(feature = "alloc-fixed-block")]
 #[global_allocator]
 pub static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
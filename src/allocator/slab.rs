@@ -0,0 +1,281 @@
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+use spin::Mutex;
+use x86_64::{instructions::interrupts::without_interrupts, structures::paging::FrameAllocator};
+
+use crate::memory::{frame_allocator, phys_to_virt};
+
+// Each slab is a single frame
+const SLAB_SIZE: usize = 4096;
+// How many caches can show up in the stats
+const MAX_CACHES: usize = 16;
+
+// Every cache that has allocated a slab, so their stats can be printed
+static CACHES: Mutex<[Option<&'static dyn SlabStatsSource>; MAX_CACHES]> =
+    Mutex::new([None; MAX_CACHES]);
+
+// Written into objects that are free
+struct FreeObject {
+    next: Option<NonNull<FreeObject>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub live_objects: usize,
+}
+
+struct SlabCacheInner {
+    free_list: Option<NonNull<FreeObject>>,
+    registered: bool,
+    slabs: usize,
+    allocs: usize,
+    frees: usize,
+    live_objects: usize,
+}
+
+// The free list only points into slab memory owned by the cache
+unsafe impl Send for SlabCacheInner {}
+
+/// A cache of objects of type T, each slab being a frame from the frame allocator
+/// Slabs are kept by the cache once allocated, so freed objects are reused by the same type
+pub struct SlabCache<T> {
+    name: &'static str,
+    inner: Mutex<SlabCacheInner>,
+    // The cache never holds a T, it only hands out memory for them
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T: 'static> SlabCache<T> {
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            inner: Mutex::new(SlabCacheInner {
+                free_list: None,
+                registered: false,
+                slabs: 0,
+                allocs: 0,
+                frees: 0,
+                live_objects: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    // Objects must be big enough to hold a FreeObject when free
+    fn object_align() -> usize {
+        mem::align_of::<T>().max(mem::align_of::<FreeObject>())
+    }
+
+    fn object_size() -> usize {
+        let size = mem::size_of::<T>().max(mem::size_of::<FreeObject>());
+        super::align_up(size, Self::object_align())
+    }
+
+    fn objects_per_slab() -> usize {
+        SLAB_SIZE / Self::object_size()
+    }
+
+    /// Moves value into the cache
+    /// Panics if no memory is left for a new slab, like Box does
+    pub fn alloc(&'static self, value: T) -> SlabBox<T> {
+        match self.try_alloc(value) {
+            Ok(slab_box) => slab_box,
+            Err(_) => panic!("Slab cache {} is out of memory", self.name),
+        }
+    }
+
+    /// Moves value into the cache, giving it back if no memory is left
+    pub fn try_alloc(&'static self, value: T) -> Result<SlabBox<T>, T> {
+        match self.alloc_object() {
+            Some(ptr) => {
+                let ptr = ptr.cast::<T>();
+                unsafe { ptr.as_ptr().write(value) };
+                Ok(SlabBox { ptr, cache: self })
+            }
+            None => Err(value),
+        }
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        // Allocating and freeing spin on the lock with interrupts off
+        without_interrupts(|| {
+            let inner = self.inner.lock();
+            SlabStats {
+                name: self.name,
+                object_size: Self::object_size(),
+                objects_per_slab: Self::objects_per_slab(),
+                slabs: inner.slabs,
+                allocs: inner.allocs,
+                frees: inner.frees,
+                live_objects: inner.live_objects,
+            }
+        })
+    }
+
+    fn alloc_object(&'static self) -> Option<NonNull<FreeObject>> {
+        // Wakers are dropped from interrupt handlers, so don't get interrupted holding the lock
+        let (object, register) = without_interrupts(|| {
+            let mut inner = self.inner.lock();
+
+            if inner.free_list.is_none() {
+                self.grow(&mut inner)?;
+            }
+
+            let object = inner.free_list.take()?;
+            inner.free_list = unsafe { object.as_ref().next };
+            inner.allocs += 1;
+            inner.live_objects += 1;
+            Some((object, !mem::replace(&mut inner.registered, true)))
+        })?;
+
+        // Only once the lock is dropped, print_slab_stats locks the caches after CACHES
+        if register {
+            register_cache(self);
+        }
+        Some(object)
+    }
+
+    // Takes a frame and splits it into free objects
+    fn grow(&'static self, inner: &mut SlabCacheInner) -> Option<()> {
+        assert!(Self::object_align() <= SLAB_SIZE && Self::objects_per_slab() > 0);
+
+        let frame = frame_allocator().allocate_frame()?;
+        let slab = phys_to_virt(frame.start_address()).as_u64() as usize;
+
+        // Link the objects in address order
+        for i in (0..Self::objects_per_slab()).rev() {
+            let object = (slab + i * Self::object_size()) as *mut FreeObject;
+            unsafe {
+                object.write(FreeObject {
+                    next: inner.free_list,
+                });
+                inner.free_list = Some(NonNull::new_unchecked(object));
+            }
+        }
+        inner.slabs += 1;
+        Some(())
+    }
+
+    // Puts an object back on the free list
+    //* Unsafe because
+    //* ptr must have come from this cache and whatever was in it already dropped
+    unsafe fn free_object(&self, ptr: NonNull<T>) {
+        without_interrupts(|| {
+            let mut inner = self.inner.lock();
+
+            let object = ptr.cast::<FreeObject>();
+            object.as_ptr().write(FreeObject {
+                next: inner.free_list,
+            });
+            inner.free_list = Some(object);
+
+            inner.frees += 1;
+            inner.live_objects = inner.live_objects.saturating_sub(1);
+        })
+    }
+}
+
+trait SlabStatsSource: Sync {
+    fn slab_stats(&self) -> SlabStats;
+}
+
+impl<T: 'static> SlabStatsSource for SlabCache<T> {
+    fn slab_stats(&self) -> SlabStats {
+        self.stats()
+    }
+}
+
+fn register_cache(cache: &'static dyn SlabStatsSource) {
+    // Caches are first used from syscalls too, which run with interrupts off
+    let registered = without_interrupts(|| {
+        let mut caches = CACHES.lock();
+        match caches.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(cache);
+                true
+            }
+            None => false,
+        }
+    });
+    if !registered {
+        println!("WARNING: too many slab caches, stats won't be shown for all of them");
+    }
+}
+
+/// Prints the stats of every slab cache that has been used
+pub fn print_slab_stats() {
+    println!(
+        "{:<16} {:>6} {:>6} {:>9} {:>9} {:>7}",
+        "Slab cache", "Size", "Slabs", "Allocs", "Frees", "Live"
+    );
+
+    // Copied so CACHES isn't held while each cache is locked
+    let caches = without_interrupts(|| *CACHES.lock());
+    for cache in caches.iter().flatten() {
+        let stats = cache.slab_stats();
+        println!(
+            "{:<16} {:>6} {:>6} {:>9} {:>9} {:>7}",
+            stats.name, stats.object_size, stats.slabs, stats.allocs, stats.frees, stats.live_objects
+        );
+    }
+}
+
+/// Owns a T stored in a slab cache, freed back to the cache on drop
+pub struct SlabBox<T: 'static> {
+    ptr: NonNull<T>,
+    cache: &'static SlabCache<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> SlabBox<T> {
+    /// Gives up ownership without dropping the value
+    pub fn into_raw(slab_box: Self) -> NonNull<T> {
+        let ptr = slab_box.ptr;
+        mem::forget(slab_box);
+        ptr
+    }
+
+    // Takes back ownership of a pointer from into_raw
+    //* Unsafe because
+    //* ptr must have come from into_raw with the same cache
+    //* and only be turned back into a SlabBox once
+    pub unsafe fn from_raw(cache: &'static SlabCache<T>, ptr: NonNull<T>) -> Self {
+        Self { ptr, cache }
+    }
+}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::drop_in_place(self.ptr.as_ptr());
+            self.cache.free_object(self.ptr);
+        }
+    }
+}
@@ -71,3 +71,59 @@ macro_rules! wrap_function_registers {
         }
     };
 }
+
+/// Same as wrap_function_registers but for exceptions which push an error code
+/// Args returned: (stack_frame: &mut InterruptStackFrame, regs: &mut Registers, error_code: u64)
+#[macro_export]
+macro_rules! wrap_function_registers_error_code {
+    ($fn: ident => $w:ident, $error: ty) => {
+        #[naked]
+        pub extern "x86-interrupt" fn $w(_: InterruptStackFrame, _: $error) {
+            unsafe {
+            asm!(
+                "push rbp",
+                "push rax",
+                "push rbx",
+                "push rcx",
+                "push rdx",
+                "push rsi",
+                "push rdi",
+                "push r8",
+                "push r9",
+                "push r10",
+                "push r11",
+                "push r12",
+                "push r13",
+                "push r14",
+                "push r15",
+                "mov rsi, rsp", // Arg #2: register list
+                "mov rdi, rsp", // Arg #1: interupt frame
+                "add rdi, 16 * 8", // Skip the registers and error code
+                "mov rdx, [rsp + 15 * 8]", // Arg #3: error code
+                "sub rsp, 8", // Keep the stack 16 byte aligned
+                "call {}",
+                "add rsp, 8",
+                "pop r15",
+                "pop r14",
+                "pop r13",
+                "pop r12",
+                "pop r11",
+                "pop r10",
+                "pop r9",
+                "pop r8",
+                "pop rdi",
+                "pop rsi",
+                "pop rdx",
+                "pop rcx",
+                "pop rbx",
+                "pop rax",
+                "pop rbp",
+                "add rsp, 8", // Pop the error code
+                "iretq",
+                sym $fn,
+                options(noreturn)
+            );
+            }
+        }
+    };
+}
@@ -1,10 +1,13 @@
-use core::convert::TryInto;
+use core::{convert::TryInto, hint::spin_loop};
 
 use alloc::vec::Vec;
 use x86_64::{instructions::port::Port, software_interrupt};
 
 use crate::syscall::yield_now;
 
+// How many times to check the status before giving up on the drive
+const POLL_LIMIT: usize = 1_000_000;
+
 #[repr(C, align(2))]
 #[derive(Debug)]
 pub struct ATADiskIdentify {
@@ -178,6 +181,89 @@ impl ATA {
         }
     }
 
+    /// Whether a drive is attached
+    pub fn is_present(&mut self) -> bool {
+        unsafe {
+            if self.master {
+                self.device.write(0xA0);
+            } else {
+                self.device.write(0xB0);
+            }
+            let status = self.command.read();
+            status != 0xFF && status != 0x00
+        }
+    }
+
+    /// Reads a whole sector into buffer
+    /// Polls instead of yielding so it can be used with interrupts disabled
+    pub fn read_sector(&mut self, sector: u32, buffer: &mut [u8; 512]) -> bool {
+        if !self.select_sector(sector, 0x20) {
+            return false;
+        }
+
+        for bytes in buffer.chunks_exact_mut(2) {
+            let data = unsafe { self.data.read() };
+            bytes[0] = (data & 0x00FF) as u8;
+            bytes[1] = ((data >> 8) & 0x00FF) as u8;
+        }
+        true
+    }
+
+    /// Writes a whole sector from buffer
+    /// Polls instead of yielding so it can be used with interrupts disabled
+    pub fn write_sector(&mut self, sector: u32, buffer: &[u8; 512]) -> bool {
+        if !self.select_sector(sector, 0x30) {
+            return false;
+        }
+
+        for bytes in buffer.chunks_exact(2) {
+            unsafe { self.data.write(bytes[0] as u16 | (bytes[1] as u16) << 8) };
+        }
+
+        // Wait for the sector to be written
+        self.wait_ready(false)
+    }
+
+    // Sends a command for a single sector using LBA28
+    // Returns once the drive is ready to transfer the data
+    fn select_sector(&mut self, sector: u32, command: u8) -> bool {
+        if sector & 0xF000_0000 != 0 || !self.wait_ready(false) {
+            return false;
+        }
+
+        // Who are we talking to?
+        let device_num: u8 = if self.master { 0xE0 } else { 0xF0 };
+        unsafe {
+            self.device
+                .write(device_num | ((sector & 0x0F00_0000) >> 24) as u8);
+            self.error.write(0);
+            self.sector_count.write(1);
+            self.lba_low.write((sector & 0x0000_00FF) as u8);
+            self.lba_mid.write(((sector & 0x0000_FF00) >> 8) as u8);
+            self.lib_hi.write(((sector & 0x00FF_0000) >> 16) as u8);
+
+            self.command.write(command);
+        }
+
+        self.wait_ready(true)
+    }
+
+    // Spins until the drive isn't busy, and has data ready if need_data is set
+    // Returns false if there was an error or the drive took too long
+    fn wait_ready(&mut self, need_data: bool) -> bool {
+        for _ in 0..POLL_LIMIT {
+            let status = unsafe { self.command.read() };
+            if status & 0x01 == 0x01 {
+                return false;
+            }
+            if status & 0x80 == 0 && (!need_data || status & 0x08 == 0x08) {
+                return true;
+            }
+            spin_loop();
+        }
+        false
+    }
+
     pub fn identify<'buf>(&mut self, buffer: &'buf mut Vec<u8>) -> Option<&'buf ATADiskIdentify> {
         unsafe {
             // Who are we talking to?
@@ -22,6 +22,17 @@ lazy_static! {
     static ref ATA_1_SLAVE: Mutex<ATA> = Mutex::new(ATA::new(0x170, false));
 }
 
+/// The drive with the given number, 0 to 3
+pub fn drive(number: u8) -> Option<&'static Mutex<ATA>> {
+    match number {
+        0 => Some(&*ATA_0_MASTER),
+        1 => Some(&*ATA_0_SLAVE),
+        2 => Some(&*ATA_1_MASTER),
+        3 => Some(&*ATA_1_SLAVE),
+        _ => None,
+    }
+}
+
 pub fn ata_identify() {
     let mut ata_0_master_info: Vec<u8> = Vec::with_capacity(512);
     let ata_0_master_info = ATA_0_MASTER.lock().identify(&mut ata_0_master_info);
@@ -11,7 +11,10 @@ use futures_util::{task::AtomicWaker, Stream, StreamExt};
 use pc_keyboard::{layouts::Us104Key, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
 
 use crate::{
+    allocator::{print_heap_stats, slab::print_slab_stats},
     disk::{ata_identify, read_screen, write_screen},
+    memory::{print_memory_map, print_memory_stats},
+    multitasking::print_tasks,
     pci::get_pci_devices,
     vga_buffer::{
         colour::{Colour, ColourCode},
@@ -292,7 +295,7 @@ pub async fn print_keypresses() {
                                 );
                                 writer::WRITER.lock().fill_screen();
                                 cursor!(0, 1);
-                                println!("Alt key HELP \n\nr: Read from disk\nw: Write to disk\nd: Show disks\np: List out PCI devices\nx: Clear screen\nc: Change display colour");
+                                println!("Alt key HELP \n\nr: Read from disk\nw: Write to disk\nd: Show disks\np: List out PCI devices\nx: Clear screen\nc: Change display colour\na: Show memory statistics\nm: Show physical memory map\nt: Show threads");
                                 alt = false;
                             }
                             DecodedKey::Unicode('r') => {
@@ -348,6 +351,43 @@ pub async fn print_keypresses() {
                                 alt = false;
                                 get_pci_devices();
                             }
+                            DecodedKey::Unicode('a') => {
+                                writer::WRITER.lock().fill_screen();
+                                writer::WRITER.lock().write_first_line(
+                                    "Success: displayed memory statistics :)",
+                                    ColourCode::from_fg(Colour::Green),
+                                );
+                                // Set cursor to top of page
+                                cursor!(0, 1);
+                                alt = false;
+                                print_memory_stats();
+                                println!();
+                                print_heap_stats();
+                                println!();
+                                print_slab_stats();
+                            }
+                            DecodedKey::Unicode('m') => {
+                                writer::WRITER.lock().fill_screen();
+                                writer::WRITER.lock().write_first_line(
+                                    "Success: displayed the physical memory map :)",
+                                    ColourCode::from_fg(Colour::Green),
+                                );
+                                // Set cursor to top of page
+                                cursor!(0, 1);
+                                alt = false;
+                                print_memory_map();
+                            }
+                            DecodedKey::Unicode('t') => {
+                                writer::WRITER.lock().fill_screen();
+                                writer::WRITER.lock().write_first_line(
+                                    "Success: displayed all the threads :)",
+                                    ColourCode::from_fg(Colour::Green),
+                                );
+                                // Set cursor to top of page
+                                cursor!(0, 1);
+                                alt = false;
+                                print_tasks();
+                            }
                             // Ignore RawKey
                             _ => {
                                 writer::WRITER.lock().write_first_line(
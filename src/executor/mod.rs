@@ -10,7 +10,7 @@ use crate::syscall::yield_now;
 
 use self::{
     spawner::Spawner,
-    task::{Task, TaskID, TaskPriority, TaskQueue, TaskWaker},
+    task::{Task, TaskID, TaskPriority, TaskQueue, TaskWaker, TASK_CACHE},
 };
 
 pub mod spawner;
@@ -20,6 +20,8 @@ use core::sync::atomic::{AtomicBool, Ordering};
 
 use alloc::collections::BTreeMap;
 
+use crate::allocator::slab::SlabBox;
+
 type SpawnerQueue = Arc<Mutex<VecDeque<QueueItem>>>;
 
 #[derive(Default, Clone, Debug)]
@@ -44,7 +46,7 @@ impl Sleep {
 }
 
 pub struct Executor {
-    tasks: BTreeMap<TaskID, Task>,
+    tasks: BTreeMap<TaskID, SlabBox<Task>>,
     task_queue: TaskQueue,
     sleep_waker: Sleep,
 }
@@ -65,16 +67,12 @@ impl Executor {
 
     fn spawn(&mut self, task: Task, priority: TaskPriority) {
         let task_id = task.id;
-        if self.tasks.insert(task_id, task).is_some() {
+        if self.tasks.insert(task_id, TASK_CACHE.alloc(task)).is_some() {
             panic!("Task with same ID already exists in tasks");
         }
 
         let task_queue = self.task_queue.get(&priority);
-        let waker = Arc::new(TaskWaker::new(
-            task_id,
-            task_queue.clone(),
-            self.sleep_waker.clone(),
-        ));
+        let waker = TaskWaker::new(task_id, task_queue.clone(), self.sleep_waker.clone());
 
         self.tasks.get_mut(&task_id).unwrap().set_waker(waker);
 
@@ -104,6 +102,9 @@ impl Executor {
 
     fn poll_task(&mut self, task_id: TaskID) {
         if let Some(task) = self.tasks.get_mut(&task_id) {
+            // Borrow the task itself so the waker and future can be borrowed separately
+            let task: &mut Task = task;
+
             // let waker = waker_cache
             //     .entry(task_id)
             //     .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
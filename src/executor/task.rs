@@ -1,19 +1,26 @@
 use core::{
     pin::Pin,
-    sync::atomic::{AtomicU64, Ordering},
-    task::Waker,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    task::{RawWaker, RawWakerVTable, Waker},
 };
 
-use alloc::{boxed::Box, sync::Arc, task::Wake};
+use alloc::boxed::Box;
 use futures_util::Future;
 use x86_64::instructions::interrupts::without_interrupts;
 
+use crate::allocator::slab::{SlabBox, SlabCache};
+
 use super::{QueueItem, Sleep, SpawnerQueue};
 
+// Tasks and wakers are small and made often, so they get their own slabs
+pub(super) static TASK_CACHE: SlabCache<Task> = SlabCache::new("executor tasks");
+static WAKER_CACHE: SlabCache<TaskWaker> = SlabCache::new("task wakers");
+
 pub struct Task {
     pub id: TaskID,
     pub(super) future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>,
-    pub(super) waker: Option<Arc<Waker>>,
+    pub(super) waker: Option<Waker>,
 }
 
 impl Task {
@@ -25,7 +32,7 @@ impl Task {
         }
     }
 
-    pub(super) fn set_waker(&mut self, waker: Arc<Waker>) {
+    pub(super) fn set_waker(&mut self, waker: Waker) {
         self.waker = Some(waker)
     }
 }
@@ -44,16 +51,26 @@ pub struct TaskWaker {
     task_id: TaskID,
     task_queue: SpawnerQueue,
     waker: Sleep,
+    // Number of Wakers pointing at this, freed when it hits 0
+    references: AtomicUsize,
 }
 
+const WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(waker_clone, waker_wake, waker_wake_by_ref, waker_drop);
+
 impl TaskWaker {
     pub(super) fn new(task_id: TaskID, task_queue: SpawnerQueue, waker: Sleep) -> Waker {
-        Waker::from(Arc::new(Self {
+        let task_waker = WAKER_CACHE.alloc(Self {
             task_id,
             task_queue,
             waker,
-        }))
+            references: AtomicUsize::new(1),
+        });
+        let ptr = SlabBox::into_raw(task_waker).as_ptr() as *const ();
+
+        unsafe { Waker::from_raw(RawWaker::new(ptr, &WAKER_VTABLE)) }
     }
+
     fn wake_task(&self) {
         without_interrupts(|| {
             self.task_queue
@@ -64,13 +81,28 @@ impl TaskWaker {
     }
 }
 
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
-    }
+unsafe fn waker_clone(ptr: *const ()) -> RawWaker {
+    let task_waker = &*(ptr as *const TaskWaker);
+    task_waker.references.fetch_add(1, Ordering::Relaxed);
+    RawWaker::new(ptr, &WAKER_VTABLE)
+}
+
+unsafe fn waker_wake(ptr: *const ()) {
+    waker_wake_by_ref(ptr);
+    waker_drop(ptr);
+}
+
+unsafe fn waker_wake_by_ref(ptr: *const ()) {
+    let task_waker = &*(ptr as *const TaskWaker);
+    task_waker.wake_task();
+}
 
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+unsafe fn waker_drop(ptr: *const ()) {
+    let task_waker = &*(ptr as *const TaskWaker);
+    if task_waker.references.fetch_sub(1, Ordering::AcqRel) == 1 {
+        // Last reference, give it back to the cache
+        let ptr = NonNull::new_unchecked(ptr as *mut TaskWaker);
+        drop(SlabBox::from_raw(&WAKER_CACHE, ptr));
     }
 }
 
@@ -2,6 +2,7 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
 lazy_static! {
     pub static ref TSS: TaskStateSegment = {
@@ -14,6 +15,15 @@ lazy_static! {
             let stack_end = stack_start + STACK_SIZE;
             stack_end
         };
+        // Page faults get their own stack so that a thread overflowing its stack can be handled
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_end = stack_start + STACK_SIZE;
+            stack_end
+        };
         tss
     };
 }
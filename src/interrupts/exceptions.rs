@@ -1,9 +1,20 @@
 use x86_64::{
     registers::control::Cr2,
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode},
+    VirtAddr,
 };
 
-use crate::{gdt::tss, hlt_loop};
+use crate::{
+    assembly::registers::Registers,
+    gdt::tss,
+    hlt_loop,
+    memory::{
+        address_space, protection,
+        vma::{self, LazyFault},
+    },
+    multitasking::{ExitStatus, TASKMANAGER},
+    wrap_function_registers_error_code,
+};
 
 pub fn set_exceptions_idt(idt: &mut InterruptDescriptorTable) {
     idt.breakpoint.set_handler_fn(breakpoint_handler);
@@ -12,7 +23,11 @@ pub fn set_exceptions_idt(idt: &mut InterruptDescriptorTable) {
             .set_handler_fn(double_fault_handler)
             .set_stack_index(tss::DOUBLE_FAULT_IST_INDEX);
     }
-    idt.page_fault.set_handler_fn(page_fault_handler);
+    unsafe {
+        idt.page_fault
+            .set_handler_fn(wrapped_page_fault_handler)
+            .set_stack_index(tss::PAGE_FAULT_IST_INDEX);
+    }
     idt.general_protection_fault
         .set_handler_fn(general_protection_handler);
 
@@ -51,17 +66,81 @@ extern "x86-interrupt" fn invalid_tss(stack_frame: InterruptStackFrame, _error_c
     panic!("EXCEPTION: INVALID TSS FAULT\n{:#?}", stack_frame);
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: PageFaultErrorCode,
+wrap_function_registers_error_code!(page_fault_handler => wrapped_page_fault_handler, PageFaultErrorCode);
+
+extern "C" fn page_fault_handler(
+    stack_frame: &mut InterruptStackFrame,
+    regs: &mut Registers,
+    error_code: u64,
 ) {
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    let addr = Cr2::read();
+
+    // A process's page table can be missing kernel mappings added since it was switched to
+    if address_space::fix_kernel_fault(addr) {
+        return;
+    }
+
+    // First touch of a reserved page or write to a shared one, map it and try again
+    let result = if address_space::in_process_region(addr) {
+        match TASKMANAGER.try_lock() {
+            Some(taskmanager) => taskmanager.handle_process_fault(addr, error_code),
+            None => LazyFault::Busy,
+        }
+    } else if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        vma::handle_kernel_fault(addr)
+    } else {
+        LazyFault::NotReserved
+    };
+
+    // Returning with interrupts enabled lets whoever holds the lock finish first
+    let interrupts_enabled = stack_frame.cpu_flags & 0x200 != 0;
+    match result {
+        LazyFault::Mapped => return,
+        LazyFault::Busy if interrupts_enabled => return,
+        LazyFault::Busy => println!("Page fault handler couldn't lock the page tables"),
+        LazyFault::OutOfMemory => println!("Out of memory mapping {:?}", addr),
+        LazyFault::SwapFailed => println!("Couldn't read {:?} back from swap", addr),
+        LazyFault::NotReserved => {}
+    }
+
+    // If a thread ran off the end of its stack only kill that thread
+    if let Some(mut taskmanager) = TASKMANAGER.try_lock() {
+        if let Some(task_id) = taskmanager.stack_overflowed(addr) {
+            println!("EXCEPTION: STACK OVERFLOW in task {:?}, killing it", task_id);
+            // Whoever joins it sees it as having panicked
+            taskmanager.exit_current(stack_frame, regs, ExitStatus::Panicked);
+            return;
+        }
+    }
+
     println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", addr);
     println!("Error Code: {:?}", error_code);
+    println!("Cause: {}", fault_cause(addr, error_code));
     println!("{:#?}", stack_frame);
     hlt_loop();
 }
 
+// Explains a page fault that couldn't be handled
+fn fault_cause(addr: VirtAddr, error_code: PageFaultErrorCode) -> &'static str {
+    let in_text = protection::kernel_read_only().contains(&addr);
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        "access to an unmapped page"
+    } else if error_code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+        "reserved bit set in a page table entry"
+    } else if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        "jump into memory that isn't executable (NX)"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && in_text {
+        "write to kernel code or read only data"
+    } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+        "write to a read only page"
+    } else {
+        "access not allowed by the page's flags"
+    }
+}
+
 pub extern "x86-interrupt" fn stack_segment_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: u64,
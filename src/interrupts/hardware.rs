@@ -1,7 +1,10 @@
 use pic8259::ChainedPics;
 use spin;
 use x86_64::{
-    instructions::{interrupts::without_interrupts, port::PortReadOnly},
+    instructions::{
+        interrupts::without_interrupts,
+        port::{Port, PortReadOnly, PortWriteOnly},
+    },
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
 };
 
@@ -38,6 +41,25 @@ impl HardwareInterruptOffset {
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC1_OFFSET, PIC2_OFFSET) });
 
+/// Timer interrupts per second, each one is a scheduler tick
+pub const TIMER_FREQUENCY: u64 = 100;
+// The PIT counts down at this rate
+const PIT_FREQUENCY: u64 = 1_193_182;
+
+// Sets channel 0 of the PIT to fire TIMER_FREQUENCY times a second instead of the default 18.2
+pub(super) fn init_timer() {
+    let divisor = (PIT_FREQUENCY / TIMER_FREQUENCY) as u16;
+    let mut command = PortWriteOnly::<u8>::new(0x43);
+    let mut channel0 = Port::<u8>::new(0x40);
+
+    unsafe {
+        // Channel 0, low then high byte, rate generator
+        command.write(0x34);
+        channel0.write(divisor as u8);
+        channel0.write((divisor >> 8) as u8);
+    }
+}
+
 pub fn set_hardware_idt(idt: &mut InterruptDescriptorTable) {
     idt[HardwareInterruptOffset::Timer.as_usize()].set_handler_fn(wrapped_timer_handler);
     idt[HardwareInterruptOffset::Keyboard.as_usize()].set_handler_fn(ps2_keyboard_handler);
@@ -23,4 +23,5 @@ lazy_static! {
 pub fn init_idt() {
     IDT.load();
     unsafe { hardware::PICS.lock().initialize() };
+    hardware::init_timer();
 }
@@ -20,10 +20,10 @@ use crafty_os::{
     disk::ata_identify,
     driver::driver_task,
     gdt, hlt_loop, interrupts,
-    memory::{self, BootInfoFrameAllocator},
-    multitasking::TASKMANAGER,
+    memory,
+    multitasking::{self, scheduler::Priority, ExitStatus, TASKMANAGER},
     pci::get_pci_devices,
-    syscall::spawn_thread,
+    syscall::{exit_thread, set_thread_name, spawn_thread, spawn_thread_with_priority},
 };
 use x86_64::{instructions::interrupts::enable as enable_interrupts, VirtAddr};
 
@@ -35,6 +35,12 @@ fn panic(info: &PanicInfo) -> ! {
 
     colour!(ColourCode::from_fg(Colour::LightRed));
     println!("{}", info);
+
+    // A panicking thread is ended and whoever joins it is told, everything else keeps running
+    if multitasking::panicking_thread().is_some() {
+        colour!();
+        exit_thread(ExitStatus::Panicked);
+    }
     hlt_loop()
 }
 
@@ -45,6 +51,12 @@ fn panic(info: &PanicInfo) -> ! {
 //     crafty_os::test::panic_handler(info)
 // }
 
+// Drive and size of the swap area, 16 MiB on ATA 0 Slave (Disk 1)
+#[cfg(feature = "swap")]
+const SWAP_DRIVE: u8 = 1;
+#[cfg(feature = "swap")]
+const SWAP_PAGES: usize = 4096;
+
 entry_point!(bootstrap);
 
 fn bootstrap(boot_info: &'static BootInfo) -> ! {
@@ -59,30 +71,45 @@ fn bootstrap(boot_info: &'static BootInfo) -> ! {
     println!("Initializing Frame Allocator...");
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
 
-    let mut mapper = unsafe { memory::init(physical_memory_offset) };
-    let mut frame_allocator = unsafe {
+    unsafe {
+        memory::init(physical_memory_offset);
         // Init the frame allocator
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::init_frame_allocator(&boot_info.memory_map)
     };
 
     println!("Initializing HEAP...");
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    allocator::init_heap().expect("Heap initialization failed");
+
+    #[cfg(feature = "swap")]
+    {
+        println!("Initializing swap...");
+        if let Err(err) = memory::swap::init(SWAP_DRIVE, 0, SWAP_PAGES) {
+            println!("WARNING: couldn't enable swap: {:?}", err);
+        }
+    }
 
     println!("Initializing Task Manager...");
 
-    TASKMANAGER.lock().init(frame_allocator, mapper);
+    TASKMANAGER.lock().init();
 
     // Start kernel is multithreaded mode
-    // Spawn driver thread
-    spawn_thread(|| {
+    // bootstrap never returns, so the handles have to be detached by hand
+    // Spawn driver thread, it handles input so it goes ahead of everything else
+    let driver = spawn_thread_with_priority(Priority::High, || {
         driver_task();
     });
+    set_thread_name(driver.id(), "driver");
+    driver.detach();
 
     // Read the PCI devices
-    spawn_thread(|| get_pci_devices());
+    let pci = spawn_thread(|| get_pci_devices());
+    set_thread_name(pci.id(), "pci");
+    pci.detach();
 
     // Perform the ATA disk check
-    spawn_thread(|| ata_identify());
+    let ata = spawn_thread(|| ata_identify());
+    set_thread_name(ata.id(), "ata");
+    ata.detach();
 
     // Perform A|B|C|D
     // spawn_thread(|| {
@@ -0,0 +1,679 @@
+use core::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use x86_64::{
+    align_up,
+    instructions::tlb,
+    registers::control::Cr3,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            mapper::{Translate, TranslateResult},
+            page_table::PageTableEntry,
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable,
+            PageTableFlags, PhysFrame, Size4KiB,
+        },
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    frame_allocator,
+    frame_allocator::BitmapFrameAllocator,
+    kernel_lvl4_frame, phys_to_virt,
+    swap::{self, SwapError},
+    try_frame_allocator,
+    vma::{map_zeroed, map_zeroed_with, page_end, unmap_vma, LazyFault, Vma, VmaError, VmaList},
+};
+
+// Level 4 entries 64..128 belong to the process, every other entry is shared with the kernel
+pub(super) const PROCESS_LVL4_ENTRIES: Range<usize> = 64..128;
+pub const PROCESS_REGION_START: u64 = 0x2000_0000_0000;
+pub const PROCESS_REGION_END: u64 = 0x4000_0000_0000;
+const PROCESS_REGION_SIZE: u64 = PROCESS_REGION_END - PROCESS_REGION_START;
+
+// Marks a page whose frame is shared, it gets its own copy when first written to
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+// How many pages to push out to swap when a fault runs out of frames
+const SWAP_OUT_BATCH: usize = 16;
+
+/// A level 4 page table
+/// The kernel's mappings are shared, the process region is private
+pub struct AddressSpace {
+    lvl4_frame: PhysFrame,
+    // The kernel's address space isn't ours to free
+    owned: bool,
+    // Reserved areas of the process region
+    vmas: VmaList,
+    // Where the clock stopped looking for pages to swap out last time
+    clock_hand: AtomicU64,
+}
+
+impl AddressSpace {
+    /// The address space the kernel booted with
+    pub fn kernel() -> Self {
+        Self {
+            lvl4_frame: kernel_lvl4_frame(),
+            owned: false,
+            vmas: VmaList::new(),
+            clock_hand: AtomicU64::new(PROCESS_REGION_START),
+        }
+    }
+
+    /// Creates an address space with an empty process region
+    pub fn new() -> Option<Self> {
+        let lvl4_frame = frame_allocator().allocate_frame()?;
+        unsafe { table_mut(lvl4_frame).zero() };
+
+        let address_space = Self {
+            lvl4_frame,
+            owned: true,
+            vmas: VmaList::new(),
+            clock_hand: AtomicU64::new(PROCESS_REGION_START),
+        };
+        address_space.sync_kernel_entries();
+        Some(address_space)
+    }
+
+    pub fn lvl4_frame(&self) -> PhysFrame {
+        self.lvl4_frame
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.lvl4_frame
+    }
+
+    /// Copies the kernel's level 4 entries so new kernel mappings show up here too
+    pub fn sync_kernel_entries(&self) {
+        if !self.owned {
+            return;
+        }
+
+        let kernel_table = unsafe { table_mut(kernel_lvl4_frame()) };
+        let table = unsafe { table_mut(self.lvl4_frame) };
+        for (index, entry) in kernel_table.iter().enumerate() {
+            if !PROCESS_LVL4_ENTRIES.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+    }
+
+    pub fn vmas(&self) -> &VmaList {
+        &self.vmas
+    }
+
+    /// Reserves memory in the process region, nothing is mapped until it's touched
+    pub fn reserve(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), VmaError> {
+        let vma = Vma::new(start, size, flags)?;
+        if !in_process_region(vma.start) || !in_process_region(vma.end - 1u64) {
+            return Err(VmaError::OutOfRegion);
+        }
+        self.vmas.insert(vma)
+    }
+
+    /// Removes a reservation and frees the pages of it that were used
+    pub fn release(&mut self, start: VirtAddr) -> Result<(), VmaError> {
+        let vma = self.vmas.remove(start).ok_or(VmaError::NotFound)?;
+        self.free_swapped(&vma);
+        unsafe { unmap_vma(&mut self.mapper(), &vma) };
+        Ok(())
+    }
+
+    /// Reserves size bytes at addr, or wherever there is room in the process region
+    /// With populate every page is mapped straight away instead of when it's touched
+    pub fn map(
+        &mut self,
+        addr: Option<VirtAddr>,
+        size: u64,
+        flags: PageTableFlags,
+        populate: bool,
+    ) -> Result<VirtAddr, VmaError> {
+        // Nothing bigger fits, and rounding it up to pages could overflow
+        if size > PROCESS_REGION_SIZE {
+            return Err(VmaError::OutOfRegion);
+        }
+
+        let start = match addr {
+            Some(addr) => addr,
+            None => self
+                .vmas
+                .find_free(
+                    align_up(size, 4096),
+                    VirtAddr::new(PROCESS_REGION_START),
+                    VirtAddr::new(PROCESS_REGION_END),
+                )
+                .ok_or(VmaError::OutOfRegion)?,
+        };
+        self.reserve(start, size, flags)?;
+
+        if populate {
+            let vma = *self.vmas.find(start).unwrap();
+            let mut mapper = unsafe { self.mapper() };
+            let mut frame_allocator = frame_allocator();
+
+            for page in vma.pages() {
+                let result =
+                    map_zeroed_with(&mut mapper, &mut frame_allocator, &vma, page.start_address());
+                if result != LazyFault::Mapped {
+                    drop(frame_allocator);
+                    drop(mapper);
+                    self.release(start)?;
+                    return Err(VmaError::OutOfMemory);
+                }
+            }
+        }
+
+        Ok(start)
+    }
+
+    /// Removes every reservation in start..start + size, freeing the pages that were used
+    /// Reservations crossing the edges are split, parts outside the range stay reserved
+    pub fn unmap(&mut self, start: VirtAddr, size: u64) -> Result<(), VmaError> {
+        let end = self.checked_range(start, size)?;
+
+        for vma in self.vmas.remove_range(start, end) {
+            self.free_swapped(&vma);
+            unsafe { unmap_vma(&mut self.mapper(), &vma) };
+        }
+        Ok(())
+    }
+
+    /// Changes the flags of start..start + size, including pages that are already mapped
+    pub fn protect(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), VmaError> {
+        let end = self.checked_range(start, size)?;
+        self.vmas.protect_range(start, end, flags)?;
+
+        let mut mapper = unsafe { self.mapper() };
+        let flags = flags | PageTableFlags::PRESENT;
+        let pages = Page::<Size4KiB>::range(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        );
+        for page in pages {
+            // Pages that haven't been touched get the new flags when they are
+            let flags = match mapper.translate(page.start_address()) {
+                // Shared pages stay read only until they are copied
+                TranslateResult::Mapped { flags: current, .. }
+                    if current.contains(COPY_ON_WRITE) =>
+                {
+                    (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+                }
+                TranslateResult::Mapped { .. } => flags,
+                _ => continue,
+            };
+
+            if let Ok(flush) = unsafe { mapper.update_flags(page, flags) } {
+                flush.flush();
+            }
+        }
+        Ok(())
+    }
+
+    // Checks a range is page aligned and in the process region, returning its end
+    fn checked_range(&self, start: VirtAddr, size: u64) -> Result<VirtAddr, VmaError> {
+        if !start.is_aligned(4096u64) {
+            return Err(VmaError::NotAligned);
+        }
+        if size == 0 {
+            return Err(VmaError::Empty);
+        }
+        if size > PROCESS_REGION_SIZE {
+            return Err(VmaError::OutOfRegion);
+        }
+
+        let end = page_end(start, size).ok_or(VmaError::OutOfRegion)?;
+        if !in_process_region(start) || !in_process_region(end - 1u64) {
+            return Err(VmaError::OutOfRegion);
+        }
+        Ok(end)
+    }
+
+    /// Handles a page fault in the process region
+    /// Reserved pages are mapped when first touched, swapped pages read back in
+    /// and shared pages copied when written to
+    pub fn handle_fault(&self, addr: VirtAddr, error_code: PageFaultErrorCode) -> LazyFault {
+        let vma = match self.vmas.find(addr) {
+            Some(vma) => vma,
+            None => return LazyFault::NotReserved,
+        };
+
+        let result = self.resolve_fault(vma, addr, error_code);
+        // Make room by pushing cold pages out to swap and try once more
+        if result == LazyFault::OutOfMemory && self.swap_out(SWAP_OUT_BATCH) > 0 {
+            return self.resolve_fault(vma, addr, error_code);
+        }
+        result
+    }
+
+    fn resolve_fault(
+        &self,
+        vma: &Vma,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> LazyFault {
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            match unsafe { self.leaf_entry(addr) } {
+                Some(entry) if swap::swapped_slot(entry).is_some() => {
+                    self.swap_in(vma, entry, addr)
+                }
+                _ => map_zeroed(unsafe { &mut self.mapper() }, vma, addr),
+            }
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && vma.flags.contains(PageTableFlags::WRITABLE)
+        {
+            self.copy_on_write(vma, addr)
+        } else {
+            LazyFault::NotReserved
+        }
+    }
+
+    // Reads a swapped out page back into a new frame
+    fn swap_in(&self, vma: &Vma, entry: &mut PageTableEntry, addr: VirtAddr) -> LazyFault {
+        let slot = match swap::swapped_slot(entry) {
+            Some(slot) => slot,
+            None => return LazyFault::Mapped,
+        };
+        let mut frame_allocator = match try_frame_allocator() {
+            Some(frame_allocator) => frame_allocator,
+            None => return LazyFault::Busy,
+        };
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return LazyFault::OutOfMemory,
+        };
+
+        match swap::read_page(slot, frame) {
+            Ok(()) => {
+                entry.set_addr(frame.start_address(), vma.flags | PageTableFlags::PRESENT);
+                tlb::flush(addr);
+                LazyFault::Mapped
+            }
+            Err(err) => {
+                unsafe { frame_allocator.deallocate_frame(frame) };
+                match err {
+                    SwapError::Busy => LazyFault::Busy,
+                    _ => LazyFault::SwapFailed,
+                }
+            }
+        }
+    }
+
+    /// Writes up to count pages that haven't been used recently out to swap, freeing their frames
+    /// Uses the clock algorithm, pages accessed since the hand last passed get another chance
+    /// Returns how many pages were swapped out
+    pub fn swap_out(&self, count: usize) -> usize {
+        if !swap::enabled() {
+            return 0;
+        }
+        let mut frame_allocator = match try_frame_allocator() {
+            Some(frame_allocator) => frame_allocator,
+            None => return 0,
+        };
+
+        let hand = self.clock_hand.load(Ordering::Relaxed);
+        let mut swapped = 0;
+        // Going round twice gives every page a chance to have its accessed bit cleared first
+        for page in self.clock_order(hand).chain(self.clock_order(hand)) {
+            if swapped == count {
+                break;
+            }
+            let addr = page.start_address();
+            let entry = match unsafe { self.leaf_entry(addr) } {
+                Some(entry) => entry,
+                None => continue,
+            };
+
+            // Shared pages would have to be swapped out of every address space using them
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(COPY_ON_WRITE) {
+                continue;
+            }
+            if flags.contains(PageTableFlags::ACCESSED) {
+                entry.set_flags(flags - PageTableFlags::ACCESSED);
+                tlb::flush(addr);
+                continue;
+            }
+
+            let frame = PhysFrame::containing_address(entry.addr());
+            match swap::write_page(frame) {
+                Ok(slot) => {
+                    swap::set_swapped(entry, slot);
+                    tlb::flush(addr);
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    swapped += 1;
+                    self.clock_hand
+                        .store((addr + 4096u64).as_u64(), Ordering::Relaxed);
+                }
+                // Out of swap or the drive isn't usable right now
+                Err(_) => break,
+            }
+        }
+        swapped
+    }
+
+    // Every reserved page, starting from the clock hand and wrapping round
+    fn clock_order(&self, hand: u64) -> impl Iterator<Item = Page> + '_ {
+        let pages = move || self.vmas.iter().flat_map(|vma| vma.pages());
+        pages()
+            .filter(move |page| page.start_address().as_u64() >= hand)
+            .chain(pages().filter(move |page| page.start_address().as_u64() < hand))
+    }
+
+    // Frees the swap slots of a VMA's pages that are swapped out
+    fn free_swapped(&self, vma: &Vma) {
+        for page in vma.pages() {
+            if let Some(entry) = unsafe { self.leaf_entry(page.start_address()) } {
+                if let Some(slot) = swap::swapped_slot(entry) {
+                    swap::free_slot(slot);
+                    entry.set_unused();
+                }
+            }
+        }
+    }
+
+    // Gives the page containing addr its own copy of its shared frame
+    fn copy_on_write(&self, vma: &Vma, addr: VirtAddr) -> LazyFault {
+        let entry = match unsafe { self.leaf_entry(addr) } {
+            Some(entry) if entry.flags().contains(COPY_ON_WRITE) => entry,
+            // Really is read only
+            _ => return LazyFault::NotReserved,
+        };
+        let mut frame_allocator = match try_frame_allocator() {
+            Some(frame_allocator) => frame_allocator,
+            None => return LazyFault::Busy,
+        };
+
+        let frame = PhysFrame::containing_address(entry.addr());
+        let flags = vma.flags | PageTableFlags::PRESENT;
+        if frame_allocator.references(frame) > 1 {
+            let copy = match frame_allocator.allocate_frame() {
+                Some(copy) => copy,
+                None => return LazyFault::OutOfMemory,
+            };
+
+            unsafe {
+                let from = phys_to_virt(frame.start_address()).as_ptr::<u8>();
+                let to = phys_to_virt(copy.start_address()).as_mut_ptr::<u8>();
+                to.copy_from_nonoverlapping(from, 4096);
+
+                entry.set_addr(copy.start_address(), flags);
+                // Drops our reference to the shared frame
+                frame_allocator.deallocate_frame(frame);
+            }
+        } else {
+            // Everything else sharing it has let go, so it can be written to directly
+            entry.set_flags(flags);
+        }
+
+        tlb::flush(addr);
+        LazyFault::Mapped
+    }
+
+    // The level 1 entry for addr, if the tables leading to it exist
+    //* Unsafe because
+    //* The entry must not be accessed through anything else while it is borrowed
+    unsafe fn leaf_entry(&self, addr: VirtAddr) -> Option<&'static mut PageTableEntry> {
+        let mut table = table_mut(self.lvl4_frame);
+        for &index in [addr.p4_index(), addr.p3_index(), addr.p2_index()].iter() {
+            let flags = table[index].flags();
+            if !flags.contains(PageTableFlags::PRESENT) || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                return None;
+            }
+            table = table_mut(PhysFrame::containing_address(table[index].addr()));
+        }
+        Some(&mut table[addr.p1_index()])
+    }
+
+    /// Creates a copy of this address space's process region
+    /// Frames are shared and made read only, each side copies a page when it writes to it
+    pub fn fork(&self) -> Option<AddressSpace> {
+        let mut child = AddressSpace::new()?;
+        child.vmas = self.vmas.clone();
+
+        let mut frame_allocator = frame_allocator();
+        let parent_table = unsafe { table_mut(self.lvl4_frame) };
+        let child_table = unsafe { table_mut(child.lvl4_frame) };
+
+        let mut shared = true;
+        for index in PROCESS_LVL4_ENTRIES {
+            let result = unsafe {
+                share_entry(
+                    &mut parent_table[index],
+                    &mut child_table[index],
+                    4,
+                    &mut frame_allocator,
+                )
+            };
+            if result.is_err() {
+                shared = false;
+                break;
+            }
+        }
+        drop(frame_allocator);
+
+        // Our writable pages are now read only
+        if self.is_active() {
+            tlb::flush_all();
+        }
+
+        if shared {
+            Some(child)
+        } else {
+            None
+        }
+    }
+
+    // A mapper for this address space, for mapping pages in the process region
+    //* Unsafe because
+    //* Only one mapper for an address space may be used at a time
+    //* Kernel mappings must still be made with the global mapper
+    pub unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        OffsetPageTable::new(table_mut(self.lvl4_frame), phys_to_virt(PhysAddr::new(0)))
+    }
+
+    // Loads this address space into CR3 if it isn't already active
+    //* Unsafe because
+    //* The code and stack currently running must be mapped in this address space
+    pub unsafe fn activate(&self) {
+        let (active, flags) = Cr3::read();
+        if active != self.lvl4_frame {
+            self.sync_kernel_entries();
+            Cr3::write(self.lvl4_frame, flags);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    /// Frees everything mapped in the process region and the page tables themselves
+    fn drop(&mut self) {
+        if !self.owned {
+            return;
+        }
+        assert!(!self.is_active(), "Can't free the active address space");
+
+        let table = unsafe { table_mut(self.lvl4_frame) };
+        let mut frame_allocator = frame_allocator();
+        unsafe {
+            for index in PROCESS_LVL4_ENTRIES {
+                free_entry(&mut table[index], 4, &mut frame_allocator);
+            }
+            frame_allocator.deallocate_frame(self.lvl4_frame);
+        }
+    }
+}
+
+// Frees what an entry in a table of the given level maps, including any tables below it
+//* Unsafe because
+//* Nothing may still be using the memory the entry maps
+unsafe fn free_entry(
+    entry: &mut PageTableEntry,
+    level: u32,
+    frame_allocator: &mut BitmapFrameAllocator,
+) {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        if let Some(slot) = swap::swapped_slot(entry) {
+            swap::free_slot(slot);
+            entry.set_unused();
+        }
+        return;
+    }
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+        // Maps memory directly, huge pages cover 512 frames per level
+        let frames = 512u64.pow(level - 1);
+        frame_allocator.deallocate_contiguous(PhysFrame::range(frame, frame + frames));
+    } else {
+        for child in table_mut(frame).iter_mut() {
+            free_entry(child, level - 1, frame_allocator);
+        }
+        frame_allocator.deallocate_frame(frame);
+    }
+    entry.set_unused();
+}
+
+// Makes child map everything parent does, sharing the frames
+// Writable pages are made read only and marked copy on write in both
+//* Unsafe because
+//* Both entries must be in tables of the given level, and child must be unused
+unsafe fn share_entry(
+    parent: &mut PageTableEntry,
+    child: &mut PageTableEntry,
+    level: u32,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), ()> {
+    let flags = parent.flags();
+    if !flags.contains(PageTableFlags::PRESENT) {
+        // Swapped out pages get their own copy in swap
+        if let Some(slot) = swap::swapped_slot(parent) {
+            swap::set_swapped(child, swap::duplicate(slot).map_err(|_| ())?);
+        }
+        return Ok(());
+    }
+
+    if level == 1 {
+        let frame = PhysFrame::containing_address(parent.addr());
+        if !frame_allocator.share_frame(frame) {
+            return Err(());
+        }
+
+        let flags = if flags.contains(PageTableFlags::WRITABLE) {
+            (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+        } else {
+            flags
+        };
+        parent.set_flags(flags);
+        child.set_addr(parent.addr(), flags);
+        return Ok(());
+    }
+
+    // Nothing maps huge pages in the process region
+    if flags.contains(PageTableFlags::HUGE_PAGE) {
+        return Err(());
+    }
+
+    // The child gets its own table at every level
+    let table_frame = frame_allocator.allocate_frame().ok_or(())?;
+    let child_table = table_mut(table_frame);
+    child_table.zero();
+    child.set_addr(table_frame.start_address(), flags);
+
+    let parent_table = table_mut(PhysFrame::containing_address(parent.addr()));
+    for (parent, child) in parent_table.iter_mut().zip(child_table.iter_mut()) {
+        share_entry(parent, child, level - 1, frame_allocator)?;
+    }
+    Ok(())
+}
+
+// Gives access to the page table stored in a frame
+//* Unsafe because
+//* The frame must hold a page table and not be accessed by anything else at the same time
+unsafe fn table_mut(frame: PhysFrame) -> &'static mut PageTable {
+    &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>()
+}
+
+pub fn in_process_region(addr: VirtAddr) -> bool {
+    addr.as_u64() >= PROCESS_REGION_START && addr.as_u64() < PROCESS_REGION_END
+}
+
+/// Checks a buffer passed to a syscall is canonical and every page of it is present in the
+/// active address space, and writable if it will be written, so touching it can't fault
+pub fn is_range_mapped(start: u64, size: u64, writable: bool) -> bool {
+    if start == 0 || size == 0 {
+        return false;
+    }
+    let (start, last) = match (VirtAddr::try_new(start), start.checked_add(size - 1)) {
+        (Ok(start), Some(last)) => (start, last),
+        _ => return false,
+    };
+    // Both ends canonical and in the same half means nothing in between is in the hole
+    let last = match VirtAddr::try_new(last) {
+        Ok(last) if (start.as_u64() ^ last.as_u64()) >> 47 == 0 => last,
+        _ => return false,
+    };
+
+    // Only reads the page tables, syscalls run with interrupts off so they can't change
+    let active = unsafe { table_mut(Cr3::read().0) };
+    let mapper = unsafe { OffsetPageTable::new(active, phys_to_virt(PhysAddr::new(0))) };
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(last),
+    );
+    for page in pages {
+        match mapper.translate(page.start_address()) {
+            TranslateResult::Mapped { flags, .. }
+                if !writable || flags.contains(PageTableFlags::WRITABLE) => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// A page fault on a kernel address can happen if the kernel added a level 4 entry
+/// after the active address space last copied them, returns true if that was fixed
+pub fn fix_kernel_fault(addr: VirtAddr) -> bool {
+    if in_process_region(addr) {
+        return false;
+    }
+
+    let (active, _) = Cr3::read();
+    let kernel = kernel_lvl4_frame();
+    if active == kernel {
+        return false;
+    }
+
+    let index = usize::from(addr.p4_index());
+    let kernel_entry = unsafe { &table_mut(kernel)[index] };
+    let entry = unsafe { &mut table_mut(active)[index] };
+    if entry.is_unused() && !kernel_entry.is_unused() {
+        *entry = kernel_entry.clone();
+        return true;
+    }
+    false
+}
+
+/// Warns if the kernel already uses part of the process region
+pub(super) fn check_process_region(kernel_table: &PageTable) {
+    for index in PROCESS_LVL4_ENTRIES {
+        if !kernel_table[index].is_unused() {
+            println!(
+                "WARNING: level 4 entry {} is used by the kernel but is in the process region",
+                index
+            );
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use core::slice;
+
+use x86_64::{
+    structures::paging::{mapper::MapToError, PageTableFlags, PhysFrame, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    huge_page,
+    layout::{self, Region},
+    with_paging,
+};
+
+const PAGE_SIZE: u64 = 4096;
+// Most bus mastering devices only take 32 bit addresses
+const DMA_LIMIT: u64 = 0x1_0000_0000;
+
+#[derive(Debug)]
+pub enum DmaError {
+    Empty,
+    // No contiguous run of frames below 4 GiB is free
+    OutOfMemory,
+    // The DMA region of the address space is full
+    NoAddressSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+/// Physically contiguous, page aligned memory below 4 GiB that a device can read and write
+/// Mapped into the kernel's DMA region and zeroed, the frames are freed when it's dropped
+pub struct DmaBuffer {
+    virt: VirtAddr,
+    phys: PhysAddr,
+    // Rounded up to whole pages
+    size: u64,
+}
+
+impl DmaBuffer {
+    pub fn new(size: usize) -> Result<Self, DmaError> {
+        if size == 0 {
+            return Err(DmaError::Empty);
+        }
+        let size = (size as u64 + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+        let virt =
+            layout::allocate(Region::Dma, size, PAGE_SIZE).ok_or(DmaError::NoAddressSpace)?;
+
+        let count = (size / PAGE_SIZE) as usize;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let mapped = with_paging(|mapper, frame_allocator| {
+            let frames = frame_allocator
+                .allocate_below(count, 1, PhysAddr::new(DMA_LIMIT))
+                .ok_or(DmaError::OutOfMemory)?;
+            let phys = frames.start.start_address();
+
+            let result = huge_page::map_physical(mapper, frame_allocator, virt, phys, size, flags);
+            if let Err(err) = result {
+                unsafe { frame_allocator.deallocate_contiguous(frames) };
+                return Err(DmaError::Map(err));
+            }
+            Ok(phys)
+        });
+        let phys = match mapped {
+            Ok(phys) => phys,
+            Err(err) => {
+                layout::free(Region::Dma, virt, size);
+                return Err(err);
+            }
+        };
+
+        // Whatever the frames held before shouldn't leak to the device
+        unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, size as usize) };
+
+        Ok(Self { virt, phys, size })
+    }
+
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    /// The address to give the device
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.virt.as_ptr(), self.size()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.size()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        let start = PhysFrame::containing_address(self.phys);
+        let frames = PhysFrame::range(start, start + self.size / PAGE_SIZE);
+        with_paging(|mapper, frame_allocator| {
+            huge_page::unmap_physical(mapper, self.virt, self.size);
+            unsafe { frame_allocator.deallocate_contiguous(frames) };
+        });
+
+        layout::free(Region::Dma, self.virt, self.size);
+    }
+}
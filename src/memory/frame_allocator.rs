@@ -0,0 +1,325 @@
+use core::slice;
+
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        frame::PhysFrameRange, FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB,
+    },
+    PhysAddr,
+};
+
+use super::phys_to_virt;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS_PER_ENTRY: usize = u64::BITS as usize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+    // Frames mapped in more than one place
+    pub shared: usize,
+}
+
+/// Totals of the bootloader's memory map, in bytes
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryMapSummary {
+    // RAM reported as usable at boot
+    pub usable: u64,
+    // Taken by the kernel image, its stack, page tables, the bootloader and boot info
+    pub kernel: u64,
+    // Reserved by the firmware or ACPI, or bad memory
+    pub reserved: u64,
+    // Usable memory the frame allocator hasn't handed out
+    pub free: u64,
+}
+
+/// A FrameAllocator that tracks every physical frame with a single bit
+/// A set bit means the frame is either in use or not usable memory
+/// Frames can also be shared, they are only freed once every reference is deallocated
+pub struct BitmapFrameAllocator {
+    memory_map: &'static MemoryMap,
+    bitmap: &'static mut [u64],
+    // References to each frame beyond the first, 0 for frames that aren't shared
+    shares: &'static mut [u8],
+    shared_frames: usize,
+    // Number of frames covered by the bitmap
+    frame_count: usize,
+    usable_frames: usize,
+    free_frames: usize,
+    // Bitmap entry to start searching from
+    next_free: usize,
+}
+
+impl BitmapFrameAllocator {
+    // Build the bitmap from the bootloader's memory map
+    //* Unsafe because
+    //* The memory map must be valid
+    //* memory::init must have been called so that physical memory is accessible
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // The bitmap only needs to reach the end of the highest usable frame
+        let highest_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (highest_addr / FRAME_SIZE) as usize;
+        let entries = (frame_count + BITS_PER_ENTRY - 1) / BITS_PER_ENTRY;
+        // The share counts go straight after the bitmap
+        let bitmap_bytes = (entries * 8 + frame_count) as u64;
+        let bitmap_frames = (bitmap_bytes + FRAME_SIZE - 1) / FRAME_SIZE;
+
+        // Store the bitmap in the first usable region that is large enough to hold it
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_frame_number - r.range.start_frame_number >= bitmap_frames)
+            .expect("No usable memory region is large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_frame_number as usize;
+        let bitmap_ptr: *mut u64 = phys_to_virt(PhysAddr::new(bitmap_region.range.start_addr()))
+            .as_mut_ptr();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, entries);
+        let shares = slice::from_raw_parts_mut(bitmap_ptr.add(entries) as *mut u8, frame_count);
+
+        // Everything starts as used, then the usable regions are freed
+        bitmap.fill(u64::MAX);
+        shares.fill(0);
+
+        let mut allocator = Self {
+            memory_map,
+            bitmap,
+            shares,
+            shared_frames: 0,
+            frame_count,
+            usable_frames: 0,
+            free_frames: 0,
+            next_free: 0,
+        };
+
+        for region in usable_regions() {
+            for index in region.range.start_frame_number..region.range.end_frame_number {
+                allocator.set_free(index as usize);
+                allocator.usable_frames += 1;
+            }
+        }
+
+        // Don't hand out the frames the bitmap lives in
+        for index in bitmap_start..bitmap_start + bitmap_frames as usize {
+            allocator.set_used(index);
+        }
+
+        allocator
+    }
+
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+
+    pub fn memory_map_summary(&self) -> MemoryMapSummary {
+        let mut summary = MemoryMapSummary {
+            usable: 0,
+            kernel: 0,
+            reserved: 0,
+            free: self.free_frames as u64 * FRAME_SIZE,
+        };
+
+        for region in self.memory_map.iter() {
+            let size = region.range.end_addr() - region.range.start_addr();
+            match region.region_type {
+                MemoryRegionType::Usable => summary.usable += size,
+                MemoryRegionType::Kernel
+                | MemoryRegionType::KernelStack
+                | MemoryRegionType::PageTable
+                | MemoryRegionType::Bootloader
+                | MemoryRegionType::BootInfo
+                | MemoryRegionType::Package => summary.kernel += size,
+                MemoryRegionType::Reserved
+                | MemoryRegionType::AcpiReclaimable
+                | MemoryRegionType::AcpiNvs
+                | MemoryRegionType::BadMemory => summary.reserved += size,
+                _ => {}
+            }
+        }
+
+        summary
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.usable_frames,
+            used: self.usable_frames - self.free_frames,
+            free: self.free_frames,
+            shared: self.shared_frames,
+        }
+    }
+
+    /// Adds a reference to a frame that is in use, so it takes an extra deallocate to free
+    /// Returns false if the frame can't be shared any more times
+    pub fn share_frame(&mut self, frame: PhysFrame) -> bool {
+        let index = Self::frame_index(frame);
+        if index >= self.frame_count || !self.is_used(index) || self.shares[index] == u8::MAX {
+            return false;
+        }
+
+        if self.shares[index] == 0 {
+            self.shared_frames += 1;
+        }
+        self.shares[index] += 1;
+        true
+    }
+
+    /// How many places a frame is used in, 0 if it is free
+    pub fn references(&self, frame: PhysFrame) -> usize {
+        let index = Self::frame_index(frame);
+        if index >= self.frame_count || !self.is_used(index) {
+            return 0;
+        }
+        self.shares[index] as usize + 1
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_ENTRY] & (1 << (index % BITS_PER_ENTRY)) != 0
+    }
+
+    fn set_used(&mut self, index: usize) {
+        if !self.is_used(index) {
+            self.bitmap[index / BITS_PER_ENTRY] |= 1 << (index % BITS_PER_ENTRY);
+            self.free_frames -= 1;
+        }
+    }
+
+    fn set_free(&mut self, index: usize) {
+        if self.is_used(index) {
+            self.bitmap[index / BITS_PER_ENTRY] &= !(1 << (index % BITS_PER_ENTRY));
+            self.free_frames += 1;
+        }
+    }
+
+    fn frame_index(frame: PhysFrame) -> usize {
+        (frame.start_address().as_u64() / FRAME_SIZE) as usize
+    }
+
+    fn index_frame(index: usize) -> PhysFrame {
+        PhysFrame::containing_address(PhysAddr::new(index as u64 * FRAME_SIZE))
+    }
+
+    /// Allocates `count` physically contiguous frames
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrameRange> {
+        self.allocate_aligned(count, 1)
+    }
+
+    /// Allocates `count` physically contiguous frames starting at a multiple of `align` frames
+    pub fn allocate_aligned(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        let limit = PhysAddr::new(self.frame_count as u64 * FRAME_SIZE);
+        self.allocate_below(count, align, limit)
+    }
+
+    /// Like allocate_aligned but every frame is below `limit`, for devices that can't reach high memory
+    pub fn allocate_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: PhysAddr,
+    ) -> Option<PhysFrameRange> {
+        if count == 0 || align == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let end = self.frame_count.min((limit.as_u64() / FRAME_SIZE) as usize);
+        let mut run_start = 0;
+        let mut index = 0;
+        while index < end {
+            if self.is_used(index) {
+                // A run can only start at the next aligned frame
+                run_start = (index / align + 1) * align;
+                index = run_start;
+                continue;
+            }
+
+            if index + 1 - run_start == count {
+                for frame in run_start..=index {
+                    self.set_used(frame);
+                }
+                return Some(PhysFrame::range(
+                    Self::index_frame(run_start),
+                    Self::index_frame(index + 1),
+                ));
+            }
+            index += 1;
+        }
+
+        // No free run is long enough
+        None
+    }
+
+    /// Allocates a 2 MiB or 1 GiB frame for a huge page
+    pub fn allocate_huge<S: PageSize>(&mut self) -> Option<PhysFrame<S>> {
+        let frames = (S::SIZE / FRAME_SIZE) as usize;
+        let range = self.allocate_aligned(frames, frames)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+
+    /// Frees a frame from allocate_huge
+    //* Unsafe because
+    //* The frame must have come from allocate_huge and no longer be in use
+    pub unsafe fn deallocate_huge<S: PageSize>(&mut self, frame: PhysFrame<S>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        self.deallocate_contiguous(PhysFrame::range(start, start + S::SIZE / FRAME_SIZE));
+    }
+
+    /// Returns every frame in the range to the allocator
+    //* Unsafe because
+    //* The frames must have been allocated by this allocator and no longer be in use
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        for frame in range {
+            self.deallocate_frame(frame);
+        }
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let entries = self.bitmap.len();
+
+        // Start from where the last free frame was found and wrap around
+        for entry in (self.next_free..entries).chain(0..self.next_free) {
+            let bits = self.bitmap[entry];
+            if bits != u64::MAX {
+                let index = entry * BITS_PER_ENTRY + (!bits).trailing_zeros() as usize;
+                self.set_used(index);
+                self.next_free = entry;
+                return Some(Self::index_frame(index));
+            }
+        }
+
+        // Out of physical memory :(
+        None
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        let index = Self::frame_index(frame);
+        if index >= self.frame_count || !self.is_used(index) {
+            println!("WARNING: tried to free unused frame {:?}", frame);
+            return;
+        }
+
+        // Shared frames only drop a reference
+        if self.shares[index] > 0 {
+            self.shares[index] -= 1;
+            if self.shares[index] == 0 {
+                self.shared_frames -= 1;
+            }
+            return;
+        }
+
+        self.set_free(index);
+        // Check this frame's entry first next time
+        self.next_free = self.next_free.min(index / BITS_PER_ENTRY);
+    }
+}
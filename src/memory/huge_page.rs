@@ -0,0 +1,272 @@
+use core::arch::x86_64::__cpuid;
+
+use x86_64::{
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, Translate, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::frame_allocator::BitmapFrameAllocator;
+
+lazy_static! {
+    // CPUID 0x8000_0001 EDX bit 26
+    static ref GIB_PAGES: bool = unsafe {
+        __cpuid(0x8000_0000).eax >= 0x8000_0001 && __cpuid(0x8000_0001).edx & (1 << 26) != 0
+    };
+}
+
+/// Whether the CPU can map 1 GiB pages
+pub fn gib_pages_supported() -> bool {
+    *GIB_PAGES
+}
+
+/// Maps fresh frames to start..start + size using the largest pages that fit
+/// 1 GiB and 2 MiB pages are used where the range is aligned for them
+/// and there is an aligned chunk of physical memory free, everything else gets 4 KiB pages
+/// On failure whatever was mapped is unmapped again
+pub fn map_region(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(start.is_aligned(Size4KiB::SIZE) && size % Size4KiB::SIZE == 0);
+    let end = start + size;
+
+    let mut addr = start;
+    while addr < end {
+        let left = end - addr;
+        let mut result = None;
+        if gib_pages_supported() && fits::<Size1GiB>(addr.as_u64(), left) {
+            result = map_fresh::<Size1GiB>(mapper, frame_allocator, addr, flags);
+        }
+        if result.is_none() && fits::<Size2MiB>(addr.as_u64(), left) {
+            result = map_fresh::<Size2MiB>(mapper, frame_allocator, addr, flags);
+        }
+        // No huge page fits, or there's no aligned chunk of physical memory for one
+        let result = match result {
+            Some(result) => result,
+            None => map_fresh_small(mapper, frame_allocator, addr, flags),
+        };
+
+        match result {
+            Ok(mapped) => addr += mapped,
+            Err(err) => {
+                unmap_region(mapper, frame_allocator, start, addr - start);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps virt..virt + size to the physical memory at phys using the largest pages that fit
+/// For memory that isn't managed by the frame allocator, like framebuffers and device registers
+pub fn map_physical(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    virt: VirtAddr,
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    assert!(virt.is_aligned(Size4KiB::SIZE) && phys.is_aligned(Size4KiB::SIZE));
+    assert!(size % Size4KiB::SIZE == 0);
+
+    let mut offset = 0;
+    while offset < size {
+        let (page_virt, page_phys) = (virt + offset, phys + offset);
+        let left = size - offset;
+        // Both addresses need the same alignment for a huge page
+        let both = page_virt.as_u64() | page_phys.as_u64();
+        let result = if gib_pages_supported() && fits::<Size1GiB>(both, left) {
+            let frame = PhysFrame::<Size1GiB>::containing_address(page_phys);
+            map_page(
+                mapper,
+                frame_allocator,
+                Page::containing_address(page_virt),
+                frame,
+                flags,
+            )
+        } else if fits::<Size2MiB>(both, left) {
+            let frame = PhysFrame::<Size2MiB>::containing_address(page_phys);
+            map_page(
+                mapper,
+                frame_allocator,
+                Page::containing_address(page_virt),
+                frame,
+                flags,
+            )
+        } else {
+            let frame = PhysFrame::<Size4KiB>::containing_address(page_phys);
+            map_page(
+                mapper,
+                frame_allocator,
+                Page::containing_address(page_virt),
+                frame,
+                flags,
+            )
+        };
+
+        match result {
+            Ok(mapped) => offset += mapped,
+            Err(err) => {
+                unmap_physical(mapper, virt, offset);
+                return Err(err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Unmaps a range mapped by map_region and frees its frames
+pub fn unmap_region(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    start: VirtAddr,
+    size: u64,
+) {
+    unmap(mapper, Some(frame_allocator), start, size)
+}
+
+/// Unmaps a range mapped by map_physical, the memory itself is left alone
+pub fn unmap_physical(mapper: &mut OffsetPageTable<'static>, start: VirtAddr, size: u64) {
+    unmap(mapper, None, start, size)
+}
+
+fn unmap(
+    mapper: &mut OffsetPageTable<'static>,
+    mut frame_allocator: Option<&mut BitmapFrameAllocator>,
+    start: VirtAddr,
+    size: u64,
+) {
+    let end = start + size;
+    let mut addr = start;
+    while addr < end {
+        let step = match mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size1GiB(_),
+                ..
+            } => {
+                if let Ok((frame, flush)) = mapper.unmap(Page::<Size1GiB>::containing_address(addr))
+                {
+                    flush.flush();
+                    if let Some(frame_allocator) = frame_allocator.as_mut() {
+                        unsafe { frame_allocator.deallocate_huge(frame) };
+                    }
+                }
+                Size1GiB::SIZE
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                if let Ok((frame, flush)) = mapper.unmap(Page::<Size2MiB>::containing_address(addr))
+                {
+                    flush.flush();
+                    if let Some(frame_allocator) = frame_allocator.as_mut() {
+                        unsafe { frame_allocator.deallocate_huge(frame) };
+                    }
+                }
+                Size2MiB::SIZE
+            }
+            TranslateResult::Mapped { .. } => {
+                if let Ok((frame, flush)) = mapper.unmap(Page::<Size4KiB>::containing_address(addr))
+                {
+                    flush.flush();
+                    if let Some(frame_allocator) = frame_allocator.as_mut() {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                    }
+                }
+                Size4KiB::SIZE
+            }
+            _ => Size4KiB::SIZE,
+        };
+
+        // Huge pages always start on their own alignment
+        addr = addr.align_down(step) + step;
+    }
+}
+
+// Whether a page of size S can go at addr with left bytes still to map
+fn fits<S: PageSize>(addr: u64, left: u64) -> bool {
+    addr % S::SIZE == 0 && left >= S::SIZE
+}
+
+// Maps a huge page to a fresh aligned chunk of physical memory
+// Returns None if there isn't a free chunk, so a smaller page can be tried
+fn map_fresh<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Option<Result<u64, MapToError<Size4KiB>>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    let frame = frame_allocator.allocate_huge::<S>()?;
+    let result = map_page(
+        mapper,
+        frame_allocator,
+        Page::containing_address(addr),
+        frame,
+        flags,
+    );
+    if result.is_err() {
+        unsafe { frame_allocator.deallocate_huge(frame) };
+    }
+    Some(result)
+}
+
+// Maps a 4 KiB page to a fresh frame
+fn map_fresh_small(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>> {
+    let frame: PhysFrame<Size4KiB> = frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    let result = map_page(
+        mapper,
+        frame_allocator,
+        Page::containing_address(addr),
+        frame,
+        flags,
+    );
+    if result.is_err() {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+    }
+    result
+}
+
+// Maps a single page of any size, returning its size
+fn map_page<S: PageSize>(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    page: Page<S>,
+    frame: PhysFrame<S>,
+    flags: PageTableFlags,
+) -> Result<u64, MapToError<Size4KiB>>
+where
+    OffsetPageTable<'static>: Mapper<S>,
+{
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(S::SIZE)
+        }
+        Err(MapToError::FrameAllocationFailed) => Err(MapToError::FrameAllocationFailed),
+        Err(MapToError::ParentEntryHugePage) => Err(MapToError::ParentEntryHugePage),
+        Err(MapToError::PageAlreadyMapped(frame)) => Err(MapToError::PageAlreadyMapped(
+            PhysFrame::containing_address(frame.start_address()),
+        )),
+    }
+}
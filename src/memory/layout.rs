@@ -0,0 +1,224 @@
+use core::ops::Range;
+
+use spin::Mutex;
+use x86_64::{
+    align_up, instructions::interrupts::without_interrupts, structures::paging::PageTable, VirtAddr,
+};
+
+use super::address_space::PROCESS_LVL4_ENTRIES;
+
+// Each region gets a whole level 4 entry to itself, 512 GiB
+const WINDOW_SIZE: u64 = 1 << 39;
+// The heap has always lived here, the other regions go in the entries after it
+const FIRST_WINDOW: usize = 136;
+// The end of entry 255 is the start of the non canonical hole, which VirtAddr can't hold
+const LAST_WINDOW: usize = 254;
+// How many freed ranges each region remembers before it starts leaking them
+const FREE_RANGES: usize = 32;
+#[cfg(feature = "kaslr")]
+const KASLR_ALIGN: u64 = 2 * 1024 * 1024;
+
+/// The parts of the kernel's address space that are handed out at runtime
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Heap,
+    Stacks,
+    Mmio,
+    Dma,
+}
+
+impl Region {
+    const ALL: [Region; 4] = [Region::Heap, Region::Stacks, Region::Mmio, Region::Dma];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Region::Heap => "Heap",
+            Region::Stacks => "Stacks",
+            Region::Mmio => "MMIO",
+            Region::Dma => "DMA",
+        }
+    }
+
+    // Whether the start of the region is moved by a random amount
+    #[cfg(feature = "kaslr")]
+    fn randomised(self) -> bool {
+        matches!(self, Region::Heap | Region::Stacks)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Window {
+    start: u64,
+    end: u64,
+    // Everything from here to the end has never been handed out
+    next: u64,
+    // Ranges that were handed out and freed again, empty ones have a size of 0
+    free: [(u64, u64); FREE_RANGES],
+}
+
+impl Window {
+    const EMPTY: Window = Window {
+        start: 0,
+        end: 0,
+        next: 0,
+        free: [(0, 0); FREE_RANGES],
+    };
+
+    fn allocate(&mut self, size: u64, align: u64) -> Option<u64> {
+        // First fit from the freed ranges
+        for i in 0..FREE_RANGES {
+            let (start, end) = self.free[i];
+            let addr = align_up(start, align);
+            if start == end || addr.checked_add(size)? > end {
+                continue;
+            }
+
+            // Keep whatever is left on either side
+            self.free[i] = (start, addr);
+            self.insert_free(addr + size, end);
+            return Some(addr);
+        }
+
+        let addr = align_up(self.next, align);
+        if addr.checked_add(size)? > self.end {
+            return None;
+        }
+        self.insert_free(self.next, addr);
+        self.next = addr + size;
+        Some(addr)
+    }
+
+    fn free(&mut self, start: u64, size: u64) {
+        if start < self.start || start + size > self.next {
+            println!(
+                "WARNING: tried to free {:#x}..{:#x} which was never allocated",
+                start,
+                start + size
+            );
+            return;
+        }
+
+        self.insert_free(start, start + size);
+    }
+
+    fn insert_free(&mut self, mut start: u64, mut end: u64) {
+        if start == end {
+            return;
+        }
+
+        // Free ranges never touch, so at most one on each side needs merging
+        for slot in self.free.iter_mut() {
+            let (free_start, free_end) = *slot;
+            if free_start != free_end && (free_end == start || free_start == end) {
+                start = start.min(free_start);
+                end = end.max(free_end);
+                *slot = (0, 0);
+            }
+        }
+
+        // Space at the end goes back to never having been handed out
+        if end == self.next {
+            self.next = start;
+            return;
+        }
+
+        match self.free.iter_mut().find(|(start, end)| start == end) {
+            Some(slot) => *slot = (start, end),
+            // Address space is plentiful, losing a range is better than allocating here
+            None => println!(
+                "WARNING: too many free ranges, leaking {:#x}..{:#x}",
+                start, end
+            ),
+        }
+    }
+}
+
+// Lock after the task manager, nothing else is locked while this is held
+static LAYOUT: Mutex<[Window; 4]> = Mutex::new([Window::EMPTY; 4]);
+
+// Gives every region an unused level 4 entry of the bootloader's page table
+// Entries used by the bootloader, like the kernel and its map of physical memory, are skipped
+pub(super) fn init(lvl4_table: &PageTable) {
+    let mut free_entries = (FIRST_WINDOW..=LAST_WINDOW)
+        .filter(|index| !PROCESS_LVL4_ENTRIES.contains(index) && lvl4_table[*index].is_unused());
+
+    let mut windows = [Window::EMPTY; 4];
+    for region in Region::ALL.iter() {
+        let index = free_entries
+            .next()
+            .expect("No free level 4 entry for the kernel's address space layout");
+        let start = index as u64 * WINDOW_SIZE;
+
+        let window = &mut windows[region.index()];
+        window.start = start;
+        window.end = start + WINDOW_SIZE;
+        window.next = start;
+
+        // Somewhere in the first half, leaving the rest for allocations
+        #[cfg(feature = "kaslr")]
+        {
+            if region.randomised() {
+                window.next += random() % (WINDOW_SIZE / 2 / KASLR_ALIGN) * KASLR_ALIGN;
+            }
+        }
+    }
+
+    without_interrupts(|| *LAYOUT.lock() = windows);
+}
+
+/// Hands out size bytes of unused address space in a region, aligned to align
+/// Nothing is mapped, None if the layout isn't set up yet or the region is full
+pub fn allocate(region: Region, size: u64, align: u64) -> Option<VirtAddr> {
+    assert!(size > 0 && align.is_power_of_two());
+
+    let addr = without_interrupts(|| LAYOUT.lock()[region.index()].allocate(size, align))?;
+    Some(VirtAddr::new(addr))
+}
+
+/// Returns address space handed out by allocate so it can be used again
+/// Whatever was mapped there must be unmapped first
+pub fn free(region: Region, start: VirtAddr, size: u64) {
+    without_interrupts(|| LAYOUT.lock()[region.index()].free(start.as_u64(), size))
+}
+
+/// Every address a region can hand out, empty before the layout is set up
+pub fn region_range(region: Region) -> Range<VirtAddr> {
+    let window = without_interrupts(|| LAYOUT.lock()[region.index()]);
+    VirtAddr::new(window.start)..VirtAddr::new(window.end)
+}
+
+/// Prints where each region is and how much of it has been handed out
+pub fn print_layout() {
+    let layout = without_interrupts(|| *LAYOUT.lock());
+
+    println!(
+        "{:<7} {:<29} {:>14}",
+        "Region", "Virtual range", "First unused"
+    );
+    for region in Region::ALL.iter() {
+        let window = &layout[region.index()];
+        println!(
+            "{:<7} {:#014x}-{:#014x} {:#014x}",
+            region.name(),
+            window.start,
+            window.end.saturating_sub(1),
+            window.next
+        );
+    }
+}
+
+// Not cryptographically strong, just enough that the layout changes between boots
+#[cfg(feature = "kaslr")]
+fn random() -> u64 {
+    use core::arch::x86_64::_rdtsc;
+    use x86_64::instructions::random::RdRand;
+
+    match RdRand::new().and_then(|rdrand| rdrand.get_u64()) {
+        Some(value) => value,
+        None => unsafe { _rdtsc() },
+    }
+}
@@ -0,0 +1,137 @@
+use core::{
+    mem::{align_of, size_of},
+    ptr::{read_volatile, write_volatile},
+};
+
+use x86_64::{
+    align_up,
+    structures::paging::{mapper::MapToError, PageTableFlags, Size4KiB},
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    huge_page,
+    layout::{self, Region},
+    with_paging,
+};
+
+const PAGE_SIZE: u64 = 4096;
+const HUGE_PAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum MmioError {
+    Empty,
+    // The range wraps around the end of physical memory
+    OutOfRange,
+    // The MMIO region of the address space is full
+    NoAddressSpace,
+    Map(MapToError<Size4KiB>),
+}
+
+/// Device memory mapped uncached into the kernel's address space
+/// Unmapped again when dropped
+pub struct Mmio {
+    // Start of the mapping, the range itself may start part way into the first page
+    mapping: VirtAddr,
+    mapping_size: u64,
+    phys: PhysAddr,
+    size: u64,
+}
+
+/// Maps size bytes of device memory at phys into the kernel with caching turned off (PCD and PWT)
+/// phys doesn't have to be page aligned, offset 0 of the result is phys
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<Mmio, MmioError> {
+    if size == 0 {
+        return Err(MmioError::Empty);
+    }
+    let end = phys
+        .as_u64()
+        .checked_add(size)
+        .ok_or(MmioError::OutOfRange)?;
+
+    let start = phys.align_down(PAGE_SIZE);
+    let mapping_size = align_up(end, PAGE_SIZE) - start.as_u64();
+    // Lets large BARs like framebuffers use huge pages
+    let align = if start.is_aligned(HUGE_PAGE_SIZE) && mapping_size >= HUGE_PAGE_SIZE {
+        HUGE_PAGE_SIZE
+    } else {
+        PAGE_SIZE
+    };
+    let mapping =
+        layout::allocate(Region::Mmio, mapping_size, align).ok_or(MmioError::NoAddressSpace)?;
+
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    let result = with_paging(|mapper, frame_allocator| {
+        huge_page::map_physical(mapper, frame_allocator, mapping, start, mapping_size, flags)
+    });
+    if let Err(err) = result {
+        layout::free(Region::Mmio, mapping, mapping_size);
+        return Err(MmioError::Map(err));
+    }
+
+    Ok(Mmio {
+        mapping,
+        mapping_size,
+        phys,
+        size,
+    })
+}
+
+impl Mmio {
+    /// Virtual address of offset 0
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.mapping + (self.phys.as_u64() - self.phys.align_down(PAGE_SIZE).as_u64())
+    }
+
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Reads a register at offset with a single access of size_of::<T>() bytes
+    pub fn read<T: Copy>(&self, offset: usize) -> T {
+        unsafe { read_volatile(self.register::<T>(offset)) }
+    }
+
+    /// Writes a register at offset with a single access of size_of::<T>() bytes
+    pub fn write<T: Copy>(&mut self, offset: usize, value: T) {
+        unsafe { write_volatile(self.register::<T>(offset), value) }
+    }
+
+    // Views the whole range as a block of registers
+    //* Unsafe because
+    //* Every field of T must be read and written volatile, like volatile::Volatile
+    //* T must match the layout the device expects
+    pub unsafe fn registers<T>(&mut self) -> &mut T {
+        &mut *self.register::<T>(0)
+    }
+
+    fn register<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset as u64 + size_of::<T>() as u64 <= self.size,
+            "MMIO access at {:#x} is past the end of the range",
+            offset
+        );
+        let addr = self.virt_addr() + offset as u64;
+        assert!(
+            addr.is_aligned(align_of::<T>() as u64),
+            "Unaligned MMIO access at {:#x}",
+            offset
+        );
+        addr.as_mut_ptr()
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        with_paging(|mapper, _| huge_page::unmap_physical(mapper, self.mapping, self.mapping_size));
+        layout::free(Region::Mmio, self.mapping, self.mapping_size);
+    }
+}
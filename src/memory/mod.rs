@@ -1,9 +1,29 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bootloader::bootinfo::MemoryMap;
 use conquer_once::spin::OnceCell;
-use spin::Mutex;
-use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{OffsetPageTable, PhysFrame};
 use x86_64::{registers::control::Cr3, structures::paging::PageTable, PhysAddr, VirtAddr};
 
+use self::frame_allocator::BitmapFrameAllocator;
+
+pub mod address_space;
+pub mod dma;
+pub mod frame_allocator;
+pub mod huge_page;
+pub mod layout;
+pub mod mmio;
+pub mod protection;
+pub mod swap;
+pub mod vma;
+
+// Where the bootloader mapped all of physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+// The level 4 table the bootloader set up, which every address space shares the kernel half of
+static KERNEL_LVL4_FRAME: AtomicU64 = AtomicU64::new(0);
+
 unsafe fn active_lvl4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     let (lv4_table, _) = Cr3::read();
 
@@ -14,48 +34,157 @@ unsafe fn active_lvl4_table(physical_memory_offset: VirtAddr) -> &'static mut Pa
     &mut *page_table_ptr
 }
 
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+pub static MAPPER: OnceCell<Mutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+
+// Create the global mapper for the active page table
+//* Unsafe because
+//* The physical memory offset must be correct
+//* Only call this once
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    KERNEL_LVL4_FRAME.store(Cr3::read().0.start_address().as_u64(), Ordering::Relaxed);
+
     let lvl4_table = active_lvl4_table(physical_memory_offset);
-    OffsetPageTable::new(lvl4_table, physical_memory_offset)
+    address_space::check_process_region(lvl4_table);
+    layout::init(lvl4_table);
+
+    // Needs to happen before anything is mapped NO_EXECUTE
+    protection::enable();
+
+    let mut mapper = OffsetPageTable::new(lvl4_table, physical_memory_offset);
+    protection::protect_kernel_image(&mut mapper);
+
+    MAPPER
+        .try_init_once(|| Mutex::new(mapper))
+        .expect("Mapper should only be initialized once");
 }
 
-/// A FrameAllocator that returns usable frams from the bootloader's memory map
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
+pub fn mapper() -> MutexGuard<'static, OffsetPageTable<'static>> {
+    MAPPER.try_get().expect("Mapper not initialized").lock()
 }
 
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        Self {
-            memory_map,
-            next: 0,
-        }
-    }
+/// Like mapper but returns None instead of spinning when it is in use
+pub fn try_mapper() -> Option<MutexGuard<'static, OffsetPageTable<'static>>> {
+    MAPPER.try_get().ok()?.try_lock()
+}
+
+/// The frame holding the kernel's level 4 page table
+pub fn kernel_lvl4_frame() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_LVL4_FRAME.load(Ordering::Relaxed)))
+}
+
+/// Returns the virtual address that a physical address is mapped to
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+pub static FRAME_ALLOCATOR: OnceCell<Mutex<BitmapFrameAllocator>> = OnceCell::uninit();
 
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // Get all regions from memory map
-        let regions = self.memory_map.iter();
+// Create the global frame allocator
+//* Unsafe because
+//* The memory map must be valid
+//* memory::init must have been called first
+pub unsafe fn init_frame_allocator(memory_map: &'static MemoryMap) {
+    FRAME_ALLOCATOR
+        .try_init_once(|| Mutex::new(BitmapFrameAllocator::init(memory_map)))
+        .expect("Frame allocator should only be initialized once");
+
+    // Now it's known how much physical memory there is
+    let physical_memory_offset = VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed));
+    protection::protect_physical_memory(
+        active_lvl4_table(physical_memory_offset),
+        physical_memory_offset,
+        memory_map,
+    );
+}
 
-        // Filter out memory regions that aren't usable
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
+pub fn frame_allocator() -> MutexGuard<'static, BitmapFrameAllocator> {
+    FRAME_ALLOCATOR
+        .try_get()
+        .expect("Frame allocator not initialized")
+        .lock()
+}
+
+/// Like frame_allocator but returns None instead of spinning when it is in use
+pub fn try_frame_allocator() -> Option<MutexGuard<'static, BitmapFrameAllocator>> {
+    FRAME_ALLOCATOR.try_get().ok()?.try_lock()
+}
 
-        // Create iterator of the range of available regions
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
+/// Runs f with the global mapper and frame allocator locked and interrupts off
+/// Syscalls spin on both with interrupts off, so a thread must never be preempted holding them
+pub fn with_paging<R>(
+    f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+) -> R {
+    without_interrupts(|| f(&mut mapper(), &mut frame_allocator()))
+}
 
-        // Transform to an iterator of frame start addresses.
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+/// Prints how much physical memory and swap is in use
+pub fn print_memory_stats() {
+    let frames = without_interrupts(|| frame_allocator().stats());
+    println!(
+        "Frames: {} used, {} free, {} total, {} shared",
+        frames.used, frames.free, frames.total, frames.shared
+    );
 
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    match swap::stats() {
+        Some(swap) => println!(
+            "Swap on drive {}: {} of {} pages used, {} swapped out, {} swapped in",
+            swap.drive, swap.used, swap.total, swap.swapped_out, swap.swapped_in
+        ),
+        None => println!("Swap: disabled"),
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+/// Prints every region of the bootloader's memory map and how much memory each kind takes up
+/// Followed by where the kernel's heap, stacks, MMIO and DMA mappings live
+pub fn print_memory_map() {
+    // Copied out so the frame allocator isn't held while printing
+    let (memory_map, summary) = without_interrupts(|| {
+        let frame_allocator = frame_allocator();
+        (
+            frame_allocator.memory_map(),
+            frame_allocator.memory_map_summary(),
+        )
+    });
+
+    println!("{:<29} {:>10} Type", "Physical range", "Size");
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        let (size, unit) = size_in_units(end - start);
+        println!(
+            "{:#014x}-{:#014x} {:>6} {:<3} {:?}",
+            start,
+            end - 1,
+            size,
+            unit,
+            region.region_type
+        );
     }
+
+    let usable = size_in_units(summary.usable);
+    let kernel = size_in_units(summary.kernel);
+    let reserved = size_in_units(summary.reserved);
+    let free = size_in_units(summary.free);
+    println!(
+        "\nUsable: {} {}  Kernel: {} {}  Reserved: {} {}  Free: {} {}",
+        usable.0, usable.1, kernel.0, kernel.1, reserved.0, reserved.1, free.0, free.1
+    );
+
+    println!();
+    layout::print_layout();
 }
 
-pub const FRAMEALLOCATOR: OnceCell<Mutex<BootInfoFrameAllocator>> = OnceCell::uninit();
+// A size in the largest unit that keeps it whole
+// Doesn't format into a String, the heap may need the frame allocator to grow
+fn size_in_units(bytes: u64) -> (u64, &'static str) {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes;
+    let mut unit = 0;
+    while unit + 1 < UNITS.len() && size >= 1024 && size % 1024 == 0 {
+        size /= 1024;
+        unit += 1;
+    }
+    (size, UNITS[unit])
+}
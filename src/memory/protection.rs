@@ -0,0 +1,195 @@
+use core::ops::Range;
+
+use bootloader::bootinfo::MemoryMap;
+use x86_64::{
+    instructions::tlb,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{
+        mapper::{MappedFrame, Translate, TranslateResult},
+        Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+// Defined by the linker
+extern "C" {
+    // Start of the kernel image, the ELF header is mapped with it
+    static __ehdr_start: u8;
+    // End of the kernel's code
+    static etext: u8;
+    // End of the kernel's data and bss
+    static end: u8;
+}
+
+// The start of an ELF file, up to the program header fields
+#[allow(dead_code)] // Only read through a pointer to the mapped image
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+}
+
+#[allow(dead_code)] // Only read through a pointer to the mapped image
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// Read only data and code of the kernel image
+pub fn kernel_read_only() -> Range<VirtAddr> {
+    unsafe {
+        let start = VirtAddr::from_ptr(&__ehdr_start).align_down(4096u64);
+        let end = VirtAddr::from_ptr(&etext).align_up(4096u64);
+        start..end
+    }
+}
+
+/// Code of the kernel image, the only part of it left executable
+/// The linker has no symbol for where the code starts, so it comes from the executable
+/// segment of the program headers, which are mapped along with the ELF header
+pub fn kernel_code() -> Range<VirtAddr> {
+    let read_only = kernel_read_only();
+    let (mut start, mut end) = (u64::MAX, 0);
+    unsafe {
+        let base = &__ehdr_start as *const u8;
+        let header = &*(base as *const ElfHeader);
+        for i in 0..header.phnum as usize {
+            let offset = header.phoff as usize + i * header.phentsize as usize;
+            let program = (base.add(offset) as *const ProgramHeader).read_unaligned();
+            if program.kind == PT_LOAD && program.flags & PF_X != 0 {
+                start = start.min(program.vaddr);
+                end = end.max(program.vaddr + program.memsz);
+            }
+        }
+    }
+
+    let code =
+        VirtAddr::new(start.min(end)).align_down(4096u64)..VirtAddr::new(end).align_up(4096u64);
+    if code.start < read_only.start || code.end > read_only.end || code.start >= code.end {
+        println!("WARNING: couldn't find the kernel's code, leaving its read only data executable");
+        return read_only;
+    }
+    code
+}
+
+/// Writable data and bss of the kernel image
+pub fn kernel_data() -> Range<VirtAddr> {
+    unsafe {
+        let start = VirtAddr::from_ptr(&etext).align_up(4096u64);
+        let end = VirtAddr::from_ptr(&end).align_up(4096u64);
+        start..end
+    }
+}
+
+// Lets NO_EXECUTE be used and makes the kernel fault on writes to read only pages
+// Copy on write depends on the kernel faulting too
+//* Unsafe because
+//* Anything already mapped that relies on being writable from the kernel must really be writable
+pub unsafe fn enable() {
+    Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+    Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+}
+
+// Makes the kernel's code and read only data read only, and everything but its code not executable
+//* Unsafe because
+//* enable must have been called first
+pub unsafe fn protect_kernel_image(mapper: &mut OffsetPageTable<'static>) {
+    let code = kernel_code();
+    for page in pages(&kernel_read_only()) {
+        if code.contains(&page.start_address()) {
+            set_flags(mapper, page, |flags| flags - PageTableFlags::WRITABLE);
+        } else {
+            set_flags(mapper, page, |flags| {
+                (flags - PageTableFlags::WRITABLE) | PageTableFlags::NO_EXECUTE
+            });
+        }
+    }
+
+    for page in pages(&kernel_data()) {
+        set_flags(mapper, page, |flags| flags | PageTableFlags::NO_EXECUTE);
+    }
+
+    tlb::flush_all();
+}
+
+// Marks the level 4 entries of the bootloader's map of physical memory as not executable
+// Everything under them inherits it, including the slabs and page tables accessed through it
+//* Unsafe because
+//* enable must have been called first
+pub unsafe fn protect_physical_memory(
+    lvl4_table: &mut PageTable,
+    physical_memory_offset: VirtAddr,
+    memory_map: &MemoryMap,
+) {
+    let highest_addr = memory_map
+        .iter()
+        .map(|region| region.range.end_addr())
+        .max()
+        .unwrap_or(0);
+    let first = physical_memory_offset.p4_index();
+    let last = (physical_memory_offset + highest_addr.max(1) - 1u64).p4_index();
+    let kernel = VirtAddr::from_ptr(&etext).p4_index();
+
+    for index in u16::from(first)..=u16::from(last) {
+        let entry = &mut lvl4_table[index as usize];
+        if index == u16::from(kernel) {
+            println!(
+                "WARNING: physical memory is mapped next to the kernel, leaving it executable"
+            );
+            continue;
+        }
+        if entry.flags().contains(PageTableFlags::PRESENT) {
+            entry.set_flags(entry.flags() | PageTableFlags::NO_EXECUTE);
+        }
+    }
+
+    tlb::flush_all();
+}
+
+fn pages(range: &Range<VirtAddr>) -> impl Iterator<Item = Page> {
+    Page::range(
+        Page::containing_address(range.start),
+        Page::containing_address(range.end),
+    )
+}
+
+// Updates the flags of a page if it is mapped with a 4 KiB page
+unsafe fn set_flags(
+    mapper: &mut OffsetPageTable<'static>,
+    page: Page<Size4KiB>,
+    update: impl Fn(PageTableFlags) -> PageTableFlags,
+) {
+    if let TranslateResult::Mapped {
+        frame: MappedFrame::Size4KiB(_),
+        flags,
+        ..
+    } = mapper.translate(page.start_address())
+    {
+        // Flushed all at once by the caller
+        if let Ok(flush) = mapper.update_flags(page, update(flags)) {
+            flush.ignore();
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use core::convert::TryFrom;
+
+use alloc::{vec, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{page_table::PageTableEntry, PageTableFlags, PhysFrame},
+    PhysAddr,
+};
+
+use crate::disk::{self, ata::ATA};
+
+use super::phys_to_virt;
+
+const PAGE_SIZE: usize = 4096;
+const SECTOR_SIZE: usize = 512;
+const SECTORS_PER_PAGE: u32 = (PAGE_SIZE / SECTOR_SIZE) as u32;
+// LBA28 can't address anything past this
+const MAX_SECTORS: u64 = 1 << 28;
+
+// Marks a non present entry whose page is in swap, the address bits hold its slot
+pub const SWAPPED: PageTableFlags = PageTableFlags::BIT_10;
+
+// The swap area, None until swap is enabled
+// Lock after the frame allocator and before the drive
+static SWAP: Mutex<Option<SwapArea>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapError {
+    NoDrive,
+    AlreadyEnabled,
+    // The area doesn't fit on an LBA28 drive, or is empty
+    OutOfRange,
+    Disabled,
+    Full,
+    // The drive reported an error reading or writing
+    Disk,
+    // The swap area or drive was locked, the access can be tried again
+    Busy,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SwapStats {
+    pub drive: u8,
+    // In pages
+    pub total: usize,
+    pub used: usize,
+    pub swapped_out: usize,
+    pub swapped_in: usize,
+}
+
+struct SwapArea {
+    drive_number: u8,
+    drive: &'static Mutex<ATA>,
+    start_sector: u32,
+    // One bit per slot, set if the slot holds a page
+    slots: Vec<u64>,
+    total: usize,
+    used: usize,
+    swapped_out: usize,
+    swapped_in: usize,
+}
+
+impl SwapArea {
+    fn allocate_slot(&mut self) -> Option<u64> {
+        let (entry, bits) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, bits)| **bits != u64::MAX)?;
+
+        let slot = entry * 64 + (!*bits).trailing_zeros() as usize;
+        if slot >= self.total {
+            return None;
+        }
+        *bits |= 1 << (slot % 64);
+        self.used += 1;
+        Some(slot as u64)
+    }
+
+    fn free_slot(&mut self, slot: u64) {
+        let slot = slot as usize;
+        if slot >= self.total || self.slots[slot / 64] & (1 << (slot % 64)) == 0 {
+            println!("WARNING: tried to free unused swap slot {}", slot);
+            return;
+        }
+        self.slots[slot / 64] &= !(1 << (slot % 64));
+        self.used -= 1;
+    }
+
+    fn sector(&self, slot: u64) -> u32 {
+        self.start_sector + slot as u32 * SECTORS_PER_PAGE
+    }
+
+    fn write_slot(&self, slot: u64, page: &[u8; PAGE_SIZE]) -> Result<(), SwapError> {
+        let mut drive = self.drive.try_lock().ok_or(SwapError::Busy)?;
+        for (i, data) in page.chunks_exact(SECTOR_SIZE).enumerate() {
+            let data = <&[u8; SECTOR_SIZE]>::try_from(data).unwrap();
+            if !drive.write_sector(self.sector(slot) + i as u32, data) {
+                return Err(SwapError::Disk);
+            }
+        }
+        Ok(())
+    }
+
+    fn read_slot(&self, slot: u64, page: &mut [u8; PAGE_SIZE]) -> Result<(), SwapError> {
+        let mut drive = self.drive.try_lock().ok_or(SwapError::Busy)?;
+        for (i, data) in page.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            let data = <&mut [u8; SECTOR_SIZE]>::try_from(data).unwrap();
+            if !drive.read_sector(self.sector(slot) + i as u32, data) {
+                return Err(SwapError::Disk);
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_slot(&self, from: u64, to: u64) -> Result<(), SwapError> {
+        let mut drive = self.drive.try_lock().ok_or(SwapError::Busy)?;
+        let mut buffer = [0; SECTOR_SIZE];
+        for i in 0..SECTORS_PER_PAGE {
+            if !drive.read_sector(self.sector(from) + i, &mut buffer)
+                || !drive.write_sector(self.sector(to) + i, &buffer)
+            {
+                return Err(SwapError::Disk);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Uses pages * 4 KiB of an ATA drive starting at start_sector as swap
+/// Anything already stored there is overwritten
+pub fn init(drive_number: u8, start_sector: u32, pages: usize) -> Result<(), SwapError> {
+    let drive = disk::drive(drive_number).ok_or(SwapError::NoDrive)?;
+    if pages == 0 || start_sector as u64 + pages as u64 * SECTORS_PER_PAGE as u64 > MAX_SECTORS {
+        return Err(SwapError::OutOfRange);
+    }
+    if !without_interrupts(|| drive.lock().is_present()) {
+        return Err(SwapError::NoDrive);
+    }
+
+    // Allocate before locking, the fault handler may need the heap
+    let area = SwapArea {
+        drive_number,
+        drive,
+        start_sector,
+        slots: vec![0; (pages + 63) / 64],
+        total: pages,
+        used: 0,
+        swapped_out: 0,
+        swapped_in: 0,
+    };
+
+    without_interrupts(|| {
+        let mut swap = SWAP.lock();
+        if swap.is_some() {
+            return Err(SwapError::AlreadyEnabled);
+        }
+        *swap = Some(area);
+        Ok(())
+    })
+}
+
+pub fn enabled() -> bool {
+    without_interrupts(|| SWAP.lock().is_some())
+}
+
+/// None if swap isn't enabled
+pub fn stats() -> Option<SwapStats> {
+    without_interrupts(|| {
+        SWAP.lock().as_ref().map(|area| SwapStats {
+            drive: area.drive_number,
+            total: area.total,
+            used: area.used,
+            swapped_out: area.swapped_out,
+            swapped_in: area.swapped_in,
+        })
+    })
+}
+
+/// The slot an entry's page was swapped out to
+pub fn swapped_slot(entry: &PageTableEntry) -> Option<u64> {
+    let flags = entry.flags();
+    if !flags.contains(PageTableFlags::PRESENT) && flags.contains(SWAPPED) {
+        Some(entry.addr().as_u64() / PAGE_SIZE as u64)
+    } else {
+        None
+    }
+}
+
+/// Makes the entry point to a slot instead of a frame
+pub fn set_swapped(entry: &mut PageTableEntry, slot: u64) {
+    entry.set_addr(PhysAddr::new(slot * PAGE_SIZE as u64), SWAPPED);
+}
+
+/// Writes the contents of a frame to a free slot, returning the slot
+/// The frame can be reused once this returns
+pub fn write_page(frame: PhysFrame) -> Result<u64, SwapError> {
+    let mut swap = SWAP.try_lock().ok_or(SwapError::Busy)?;
+    let area = swap.as_mut().ok_or(SwapError::Disabled)?;
+
+    let slot = area.allocate_slot().ok_or(SwapError::Full)?;
+    let page = unsafe { &*phys_to_virt(frame.start_address()).as_ptr::<[u8; PAGE_SIZE]>() };
+    if let Err(err) = area.write_slot(slot, page) {
+        area.free_slot(slot);
+        return Err(err);
+    }
+
+    area.swapped_out += 1;
+    Ok(slot)
+}
+
+/// Reads a slot back into a frame and frees the slot
+pub fn read_page(slot: u64, frame: PhysFrame) -> Result<(), SwapError> {
+    let mut swap = SWAP.try_lock().ok_or(SwapError::Busy)?;
+    let area = swap.as_mut().ok_or(SwapError::Disabled)?;
+
+    let page = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<[u8; PAGE_SIZE]>() };
+    area.read_slot(slot, page)?;
+
+    area.free_slot(slot);
+    area.swapped_in += 1;
+    Ok(())
+}
+
+/// Copies a slot into a new one, for an address space that needs its own copy
+pub fn duplicate(slot: u64) -> Result<u64, SwapError> {
+    let mut swap = SWAP.try_lock().ok_or(SwapError::Busy)?;
+    let area = swap.as_mut().ok_or(SwapError::Disabled)?;
+
+    let copy = area.allocate_slot().ok_or(SwapError::Full)?;
+    if let Err(err) = area.copy_slot(slot, copy) {
+        area.free_slot(copy);
+        return Err(err);
+    }
+    Ok(copy)
+}
+
+/// Frees a slot whose page is no longer needed
+pub fn free_slot(slot: u64) {
+    without_interrupts(|| {
+        if let Some(area) = SWAP.lock().as_mut() {
+            area.free_slot(slot);
+        }
+    })
+}
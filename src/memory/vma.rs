@@ -0,0 +1,309 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::paging::{
+        mapper::MapToError, page::PageRange, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{
+    frame_allocator, frame_allocator::BitmapFrameAllocator, mapper, phys_to_virt,
+    try_frame_allocator, try_mapper,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    NotAligned,
+    Empty,
+    Overlaps,
+    NotFound,
+    // The range isn't in the part of the address space being reserved from
+    OutOfRegion,
+    OutOfMemory,
+}
+
+/// What happened when a page fault was checked against the reserved areas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LazyFault {
+    Mapped,
+    NotReserved,
+    // A lock needed to map the page was held, the access can be tried again
+    Busy,
+    OutOfMemory,
+    // The page is in swap but couldn't be read back
+    SwapFailed,
+}
+
+/// A reserved range of virtual memory
+/// Pages in it are mapped to a zeroed frame the first time they are touched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    // Flags each page is mapped with, PRESENT is added when mapping
+    pub flags: PageTableFlags,
+}
+
+impl Vma {
+    /// Size is rounded up to whole pages
+    pub fn new(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<Self, VmaError> {
+        if !start.is_aligned(4096u64) {
+            return Err(VmaError::NotAligned);
+        }
+        if size == 0 {
+            return Err(VmaError::Empty);
+        }
+
+        Ok(Self {
+            start,
+            end: page_end(start, size).ok_or(VmaError::OutOfRegion)?,
+            flags: flags - PageTableFlags::PRESENT,
+        })
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end),
+        )
+    }
+}
+
+/// The page aligned end of size bytes from start
+/// None if it would overflow or isn't a canonical address
+pub fn page_end(start: VirtAddr, size: u64) -> Option<VirtAddr> {
+    let end = start.as_u64().checked_add(size)?.checked_add(4095)? & !4095;
+    VirtAddr::try_new(end).ok()
+}
+
+/// Every VMA of an address space, sorted by start address
+#[derive(Clone)]
+pub struct VmaList {
+    areas: BTreeMap<u64, Vma>,
+}
+
+impl VmaList {
+    pub fn new() -> Self {
+        Self {
+            areas: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, vma: Vma) -> Result<(), VmaError> {
+        // Only the area starting closest below the end can overlap
+        if let Some((_, other)) = self.areas.range(..vma.end.as_u64()).next_back() {
+            if other.end > vma.start {
+                return Err(VmaError::Overlaps);
+            }
+        }
+
+        self.areas.insert(vma.start.as_u64(), vma);
+        Ok(())
+    }
+
+    pub fn find(&self, addr: VirtAddr) -> Option<&Vma> {
+        self.areas
+            .range(..=addr.as_u64())
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+
+    /// Removes the VMA starting at start
+    pub fn remove(&mut self, start: VirtAddr) -> Option<Vma> {
+        self.areas.remove(&start.as_u64())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Vma> {
+        self.areas.values()
+    }
+
+    /// Whether every address in start..end is reserved
+    pub fn covers(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        let mut addr = start;
+        while addr < end {
+            match self.find(addr) {
+                Some(vma) => addr = vma.end,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Finds the lowest gap of at least size bytes between start and end
+    pub fn find_free(&self, size: u64, start: VirtAddr, end: VirtAddr) -> Option<VirtAddr> {
+        let mut candidate = start;
+        for vma in self.areas.values() {
+            if vma.end <= candidate {
+                continue;
+            }
+            if vma.start.as_u64() >= candidate.as_u64().checked_add(size)? {
+                break;
+            }
+            candidate = vma.end;
+        }
+
+        match candidate.as_u64().checked_add(size) {
+            Some(candidate_end) if candidate_end <= end.as_u64() => Some(candidate),
+            _ => None,
+        }
+    }
+
+    // Splits the VMA containing addr so that a VMA starts exactly at addr
+    fn split_at(&mut self, addr: VirtAddr) {
+        if let Some(&vma) = self.find(addr) {
+            if vma.start != addr {
+                self.areas.get_mut(&vma.start.as_u64()).unwrap().end = addr;
+                self.areas.insert(addr.as_u64(), Vma { start: addr, ..vma });
+            }
+        }
+    }
+
+    /// Removes everything reserved in start..end, VMAs crossing the edges are split
+    /// Returns the parts that were removed
+    pub fn remove_range(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<Vma> {
+        self.split_at(start);
+        self.split_at(end);
+
+        let starts: Vec<u64> = self
+            .areas
+            .range(start.as_u64()..end.as_u64())
+            .map(|(&start, _)| start)
+            .collect();
+        starts
+            .iter()
+            .filter_map(|start| self.areas.remove(start))
+            .collect()
+    }
+
+    /// Changes the flags of everything in start..end, which must all be reserved
+    pub fn protect_range(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), VmaError> {
+        if !self.covers(start, end) {
+            return Err(VmaError::NotFound);
+        }
+
+        self.split_at(start);
+        self.split_at(end);
+        for (_, vma) in self.areas.range_mut(start.as_u64()..end.as_u64()) {
+            vma.flags = flags - PageTableFlags::PRESENT;
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    // Areas reserved in the kernel's half, shared by every address space
+    static ref KERNEL_VMAS: Mutex<VmaList> = Mutex::new(VmaList::new());
+}
+
+/// Reserves memory in the kernel's half of the address space
+/// Nothing is mapped until it's touched
+pub fn reserve_kernel(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<(), VmaError> {
+    let vma = Vma::new(start, size, flags)?;
+    if super::address_space::in_process_region(vma.start)
+        || super::address_space::in_process_region(vma.end - 1u64)
+    {
+        return Err(VmaError::OutOfRegion);
+    }
+
+    // Don't get interrupted holding the lock, the page fault handler needs it
+    without_interrupts(|| KERNEL_VMAS.lock().insert(vma))
+}
+
+/// Removes a kernel reservation and frees the pages of it that were used
+pub fn release_kernel(start: VirtAddr) -> Result<(), VmaError> {
+    let vma = without_interrupts(|| KERNEL_VMAS.lock().remove(start)).ok_or(VmaError::NotFound)?;
+    // Interrupts stay off while the page tables are locked, like with_paging
+    without_interrupts(|| unsafe { unmap_vma(&mut mapper(), &vma) });
+    Ok(())
+}
+
+// Unmaps every page of the VMA that was mapped and frees its frame
+//* Unsafe because
+//* Nothing may still be using the memory
+pub unsafe fn unmap_vma(mapper: &mut OffsetPageTable<'static>, vma: &Vma) {
+    for page in vma.pages() {
+        // Pages that were never touched aren't mapped
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.flush();
+            frame_allocator().deallocate_frame(frame);
+        }
+    }
+}
+
+/// Maps the page of a fault in the kernel's half if it has been reserved
+pub fn handle_kernel_fault(addr: VirtAddr) -> LazyFault {
+    let vma = match KERNEL_VMAS.try_lock() {
+        Some(vmas) => match vmas.find(addr) {
+            Some(vma) => *vma,
+            None => return LazyFault::NotReserved,
+        },
+        None => return LazyFault::Busy,
+    };
+
+    match try_mapper() {
+        Some(mut mapper) => map_zeroed(&mut mapper, &vma, addr),
+        None => LazyFault::Busy,
+    }
+}
+
+/// Maps a zeroed frame to the page containing addr using the VMA's flags
+pub fn map_zeroed(mapper: &mut OffsetPageTable<'static>, vma: &Vma, addr: VirtAddr) -> LazyFault {
+    match try_frame_allocator() {
+        Some(mut frame_allocator) => map_zeroed_with(mapper, &mut frame_allocator, vma, addr),
+        None => LazyFault::Busy,
+    }
+}
+
+/// Like map_zeroed but with a frame allocator that is already locked
+pub fn map_zeroed_with(
+    mapper: &mut OffsetPageTable<'static>,
+    frame_allocator: &mut BitmapFrameAllocator,
+    vma: &Vma,
+    addr: VirtAddr,
+) -> LazyFault {
+    let frame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return LazyFault::OutOfMemory,
+    };
+
+    unsafe {
+        // Don't leak whatever the frame was used for before
+        phys_to_virt(frame.start_address())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, 4096);
+
+        let page: Page<Size4KiB> = Page::containing_address(addr);
+        let flags = vma.flags | PageTableFlags::PRESENT;
+        match mapper.map_to(page, frame, flags, frame_allocator) {
+            Ok(flush) => {
+                flush.flush();
+                LazyFault::Mapped
+            }
+            Err(err) => {
+                frame_allocator.deallocate_frame(frame);
+                match err {
+                    // Nothing to do, the access can just be retried
+                    MapToError::PageAlreadyMapped(_) => LazyFault::Mapped,
+                    _ => LazyFault::OutOfMemory,
+                }
+            }
+        }
+    }
+}
@@ -1,20 +1,34 @@
+pub mod process;
+pub mod scheduler;
+pub mod stack;
+pub mod sync;
 pub mod task;
 pub mod taskmanager;
+pub mod timer;
 
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::sync::atomic::{AtomicUsize, Ordering};
 
-use alloc::{
-    collections::{BTreeMap, VecDeque},
-    sync::Arc,
-};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
 use spin::Mutex;
-use x86_64::structures::{idt::InterruptStackFrameValue, paging::OffsetPageTable};
+use x86_64::{
+    instructions::interrupts::{self, without_interrupts},
+    structures::idt::InterruptStackFrameValue,
+};
 
-use crate::{assembly::registers::Registers, memory::BootInfoFrameAllocator};
+use crate::{
+    allocator::slab::{SlabBox, SlabCache},
+    assembly::registers::Registers,
+    interrupts::hardware::TIMER_FREQUENCY,
+    syscall,
+};
 
-// Start stack at this address
-static STACK_ADDR: AtomicU64 = AtomicU64::new(0x10_000_000);
-const STACK_SIZE: usize = 4096;
+use self::{
+    process::Process,
+    scheduler::{Priority, RunQueues},
+    stack::Stack,
+    sync::{SyncID, SyncObject},
+    timer::TimerWheel,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct TaskID(usize);
@@ -44,10 +58,200 @@ impl From<usize> for TaskID {
     }
 }
 
+impl From<TaskID> for usize {
+    fn from(id: TaskID) -> Self {
+        id.0
+    }
+}
+
+/// How a thread ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    // Its function returned
+    Exited = 0,
+    Panicked = 1,
+    Killed = 2,
+}
+
+impl ExitStatus {
+    const ALL: [ExitStatus; 3] = [ExitStatus::Exited, ExitStatus::Panicked, ExitStatus::Killed];
+
+    pub(crate) fn from_usize(value: usize) -> Option<Self> {
+        Self::ALL.get(value).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProcessID(usize);
+
+impl ProcessID {
+    pub fn new() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // We use ProcessID of 0 for the kernel
+    pub const fn kernel() -> Self {
+        Self(0)
+    }
+
+    pub fn is_kernel(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl From<usize> for ProcessID {
+    fn from(id: usize) -> Self {
+        Self(id)
+    }
+}
+
+impl From<ProcessID> for usize {
+    fn from(id: ProcessID) -> Self {
+        id.0
+    }
+}
+
+/// What a thread is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Running,
+    // In a run queue
+    Ready,
+    // In the timer wheel
+    Sleeping,
+    // Waiting in a join or on a mutex, semaphore or condvar
+    Blocked,
+}
+
+impl TaskState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskState::Running => "Running",
+            TaskState::Ready => "Ready",
+            TaskState::Sleeping => "Sleeping",
+            TaskState::Blocked => "Blocked",
+        }
+    }
+}
+
+// Longest name a TaskInfo has room for
+const TASK_NAME_LEN: usize = 16;
+
+/// A copy of what the task manager knows about a thread
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TaskInfo {
+    pub id: TaskID,
+    pub process: ProcessID,
+    pub priority: Priority,
+    pub state: TaskState,
+    // Timer ticks it has spent running
+    pub ticks: u64,
+    // The tick it was spawned on
+    pub spawned: u64,
+    name: [u8; TASK_NAME_LEN],
+    name_len: usize,
+}
+
+impl TaskInfo {
+    fn new(task: &Task) -> Self {
+        let mut info = Self {
+            id: task.id,
+            process: task.process,
+            priority: task.priority,
+            state: task.state,
+            ticks: task.ticks,
+            spawned: task.spawned,
+            name: [0; TASK_NAME_LEN],
+            name_len: 0,
+        };
+
+        if let Some(name) = &task.name {
+            // Cut long names short without splitting a character
+            let mut len = name.len().min(TASK_NAME_LEN);
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            info.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+            info.name_len = len;
+        }
+        info
+    }
+
+    /// Empty if the thread hasn't been named
+    pub fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.name_len]).unwrap_or("")
+    }
+}
+
 pub struct Task {
     pub id: TaskID,
+    pub process: ProcessID,
+    pub priority: Priority,
+    pub state: TaskState,
+    // Timer ticks it has spent running
+    pub ticks: u64,
+    // The tick it was spawned on
+    pub spawned: u64,
+    // Shown by ps
+    pub name: Option<String>,
+    // Nothing will join it, so how it ended doesn't need to be kept
+    detached: bool,
     state_isf: InterruptStackFrameValue,
     state_reg: Registers,
+    stack: Stack,
+}
+
+// Tasks are kept out of the heap to keep it from fragmenting
+static TASK_CACHE: SlabCache<Task> = SlabCache::new("threads");
+
+/// The running thread, if a panic can end just that thread
+/// None if the panic is in an interrupt handler, or the task manager is locked or not running a thread
+pub fn panicking_thread() -> Option<TaskID> {
+    if !interrupts::are_enabled() {
+        return None;
+    }
+
+    let current = without_interrupts(|| TASKMANAGER.try_lock().map(|tm| tm.current_task()))?;
+    Some(current).filter(|task| !task.is_none())
+}
+
+/// Lists every thread, what it's doing and how much of the CPU it has had, like ps
+pub fn print_tasks() {
+    let tasks = syscall::task_list();
+    let now = timer::ticks().max(1);
+
+    println!(
+        "{:>4} {:>4} {:<6} {:<8} {:>8} {:>4} {:>9} Name",
+        "ID", "PID", "Prio", "State", "Ticks", "CPU", "Started"
+    );
+    for task in tasks.iter() {
+        println!(
+            "{:>4} {:>4} {:<6} {:<8} {:>8} {:>3}% {:>6}.{:02}s {}",
+            usize::from(task.id),
+            usize::from(task.process),
+            task.priority.name(),
+            task.state.name(),
+            task.ticks,
+            task.ticks * 100 / now,
+            task.spawned / TIMER_FREQUENCY,
+            task.spawned % TIMER_FREQUENCY * 100 / TIMER_FREQUENCY,
+            task.name()
+        );
+    }
+
+    // The idle task only runs when nothing else is ready
+    let idle = tasks
+        .iter()
+        .find(|task| task.id.is_none())
+        .map_or(0, |task| task.ticks);
+    println!(
+        "\n{} threads, CPU idle {}% of {} ticks",
+        tasks.len(),
+        idle * 100 / now,
+        now
+    );
 }
 
 lazy_static! {
@@ -57,12 +261,25 @@ lazy_static! {
 // pub const TASKMANAGER: OnceCell<Mutex<TaskManager>> = OnceCell::uninit();
 
 struct TaskManagerInit {
-    frame_allocator: BootInfoFrameAllocator,
-    mapper: OffsetPageTable<'static>,
+    // Stack slots of threads that have quit, ready to be reused
+    free_stacks: Vec<u64>,
 }
 pub struct TaskManager {
-    tasks: BTreeMap<TaskID, Task>,
-    task_queue: Arc<Mutex<VecDeque<TaskID>>>,
+    tasks: BTreeMap<TaskID, SlabBox<Task>>,
+    processes: BTreeMap<ProcessID, Process>,
+    run_queues: RunQueues,
     current_task: TaskID,
+    // Whether the none task was switched to, rather than being whatever ran before the first switch
+    idle_running: bool,
+    // Threads that are sleeping, out of the run queues until their deadline
+    timers: TimerWheel,
+    // How threads that haven't been joined or detached yet ended
+    exited: BTreeMap<TaskID, ExitStatus>,
+    // Threads waiting in join, by the thread they are waiting for
+    joiners: BTreeMap<TaskID, TaskID>,
+    // Mutexes, semaphores and condvars with the threads blocked on them
+    sync_objects: BTreeMap<SyncID, SyncObject>,
+    // Tasks that have quit but whose stacks haven't been freed yet
+    dead_tasks: Vec<SlabBox<Task>>,
     dynamic: Option<TaskManagerInit>,
 }
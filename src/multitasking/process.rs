@@ -0,0 +1,49 @@
+use crate::memory::address_space::AddressSpace;
+
+use super::ProcessID;
+
+/// A group of threads sharing an address space
+pub struct Process {
+    pub id: ProcessID,
+    address_space: AddressSpace,
+    // Threads that haven't been freed yet, the process is freed with its last thread
+    pub(super) threads: usize,
+}
+
+impl Process {
+    /// The process kernel threads belong to, using the kernel's page table
+    pub fn kernel() -> Self {
+        Self {
+            id: ProcessID::kernel(),
+            address_space: AddressSpace::kernel(),
+            threads: 0,
+        }
+    }
+
+    /// Creates a process with its own address space
+    /// Returns None if there isn't a frame left for its page table
+    pub fn new() -> Option<Self> {
+        Some(Self {
+            id: ProcessID::new(),
+            address_space: AddressSpace::new()?,
+            threads: 0,
+        })
+    }
+
+    /// Creates a process whose memory is a copy on write copy of this one's
+    pub fn fork(&self) -> Option<Self> {
+        Some(Self {
+            id: ProcessID::new(),
+            address_space: self.address_space.fork()?,
+            threads: 0,
+        })
+    }
+
+    pub fn address_space(&self) -> &AddressSpace {
+        &self.address_space
+    }
+
+    pub fn address_space_mut(&mut self) -> &mut AddressSpace {
+        &mut self.address_space
+    }
+}
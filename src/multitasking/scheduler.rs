@@ -0,0 +1,100 @@
+use alloc::collections::VecDeque;
+
+use super::TaskID;
+
+// A thread that has been ready this many ticks without running moves up a queue
+pub const AGING_TICKS: u64 = 20;
+
+/// How urgently a thread wants the CPU
+/// The highest priority ready thread always runs, lower ones only get a turn once they have aged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Low = 0,
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    const COUNT: usize = 3;
+    const ALL: [Priority; Priority::COUNT] = [Priority::Low, Priority::Normal, Priority::High];
+
+    pub(crate) fn from_usize(value: usize) -> Option<Self> {
+        Self::ALL.get(value).copied()
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Priority::Low => "Low",
+            Priority::Normal => "Normal",
+            Priority::High => "High",
+        }
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+struct Queued {
+    id: TaskID,
+    // The tick it was put in this queue
+    since: u64,
+}
+
+/// One queue of ready threads per priority
+pub(super) struct RunQueues {
+    queues: [VecDeque<Queued>; Priority::COUNT],
+}
+
+impl RunQueues {
+    pub fn new() -> Self {
+        Self {
+            queues: [
+                VecDeque::with_capacity(25),
+                VecDeque::with_capacity(100),
+                VecDeque::with_capacity(25),
+            ],
+        }
+    }
+
+    pub fn push(&mut self, id: TaskID, priority: Priority, now: u64) {
+        self.queues[priority as usize].push_back(Queued { id, since: now });
+    }
+
+    /// Takes the thread that has waited longest in the highest non empty queue
+    pub fn pop(&mut self) -> Option<TaskID> {
+        self.queues
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+            .map(|queued| queued.id)
+    }
+
+    /// Takes a thread out of whichever queue it is in
+    pub fn remove(&mut self, id: TaskID) -> bool {
+        for queue in self.queues.iter_mut() {
+            if let Some(index) = queue.iter().position(|queued| queued.id == id) {
+                queue.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Moves threads that have waited too long up a queue so they can't starve
+    /// They drop back to their own priority once they have run
+    pub fn age(&mut self, now: u64) {
+        for level in (0..Priority::COUNT - 1).rev() {
+            // Queues are in the order threads were added, so the oldest are at the front
+            while let Some(queued) = self.queues[level].front() {
+                if now - queued.since < AGING_TICKS {
+                    break;
+                }
+                let id = self.queues[level].pop_front().unwrap().id;
+                self.queues[level + 1].push_back(Queued { id, since: now });
+            }
+        }
+    }
+}
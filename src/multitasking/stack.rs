@@ -0,0 +1,124 @@
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageTableFlags,
+        Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::memory::{
+    vma::{release_kernel, reserve_kernel},
+    with_paging,
+};
+
+pub const DEFAULT_STACK_SIZE: usize = 4096 * 4; // 16 KiB
+pub const MAX_STACK_SIZE: usize = 4096 * 64; // 256 KiB
+const GUARD_PAGE_SIZE: u64 = 4096;
+
+// Every stack gets a slot big enough for the largest stack and its guard page
+// So a freed slot can be reused by a stack of any size
+pub(super) const STACK_SLOT_SIZE: u64 = MAX_STACK_SIZE as u64 + GUARD_PAGE_SIZE;
+
+#[derive(Debug)]
+pub enum StackError {
+    // There is no address space left for another stack slot
+    NoSlot,
+    // The slot already has a stack reserved in it
+    SlotInUse,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        StackError::Map(err)
+    }
+}
+
+pub struct Stack {
+    slot: VirtAddr,
+    // Lowest mapped address of the stack
+    bottom: VirtAddr,
+    top: VirtAddr,
+}
+
+impl Stack {
+    /// Reserves a stack at the top of the slot and maps all of it
+    /// The page below the stack is always left unmapped so overflows page fault
+    /// Nothing is left to be faulted in, a thread faulting on its stack while holding
+    /// the page table locks could never be handled
+    pub fn new(slot: VirtAddr, size: usize) -> Result<Self, StackError> {
+        // Round up to whole pages
+        let size = ((size as u64 + 4095) / 4096) * 4096;
+        assert!(size > 0 && size <= MAX_STACK_SIZE as u64);
+
+        let top = slot + STACK_SLOT_SIZE;
+        let bottom = top - size;
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+        if reserve_kernel(bottom, size, flags).is_err() {
+            return Err(StackError::SlotInUse);
+        }
+        let stack = Self { slot, bottom, top };
+
+        if let Err(err) = Self::map_eagerly(bottom, top, flags) {
+            // Give back what we managed to map
+            unsafe { stack.free() };
+            return Err(err.into());
+        }
+
+        Ok(stack)
+    }
+
+    fn map_eagerly(
+        start: VirtAddr,
+        end: VirtAddr,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        with_paging(|mapper, frame_allocator| {
+            let pages = Page::range(
+                Page::containing_address(start),
+                Page::containing_address(end),
+            );
+            for page in pages {
+                let frame = frame_allocator
+                    .allocate_frame()
+                    .ok_or(MapToError::FrameAllocationFailed)?;
+
+                match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                    Ok(flush) => flush.flush(),
+                    Err(err) => {
+                        unsafe { frame_allocator.deallocate_frame(frame) };
+                        return Err(err);
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    pub fn slot(&self) -> VirtAddr {
+        self.slot
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.top
+    }
+
+    /// Whether an access to addr means the stack has overflowed
+    /// Anything in the slot below the stack counts, not just the guard page,
+    /// as a large stack frame can skip straight over the guard page
+    pub fn is_overflow(&self, addr: VirtAddr) -> bool {
+        addr >= self.slot && addr < self.bottom
+    }
+
+    // Unmaps the stack and gives its frames back to the frame allocator
+    //* Unsafe because
+    //* The stack must no longer be in use
+    pub unsafe fn free(&self) {
+        if let Err(err) = release_kernel(self.bottom) {
+            println!("Failed to free stack at {:?}: {:?}", self.bottom, err);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+use core::{
+    cell::UnsafeCell,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use alloc::collections::VecDeque;
+
+use crate::syscall::{self, PENDING};
+
+use super::TaskID;
+
+// Kinds of object sync_create can make
+pub(crate) const SYNC_MUTEX: usize = 0;
+pub(crate) const SYNC_SEMAPHORE: usize = 1;
+pub(crate) const SYNC_CONDVAR: usize = 2;
+
+// Results of the sync syscalls
+pub(crate) const SYNC_FAILED: usize = 0;
+pub(crate) const SYNC_DONE: usize = 1;
+
+/// Identifies a mutex, semaphore or condvar whose wait queue the kernel keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SyncID(usize);
+
+impl SyncID {
+    pub(super) fn new() -> Self {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+        Self(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl From<usize> for SyncID {
+    fn from(id: usize) -> Self {
+        Self(id)
+    }
+}
+
+impl From<SyncID> for usize {
+    fn from(id: SyncID) -> Self {
+        id.0
+    }
+}
+
+/// The kernel's side of a sync object, threads waiting on it are off the run queues
+pub(super) enum SyncObject {
+    Mutex {
+        owner: Option<TaskID>,
+        waiters: VecDeque<TaskID>,
+    },
+    Semaphore {
+        permits: usize,
+        waiters: VecDeque<TaskID>,
+    },
+    // Each waiter has the mutex it has to get back before it can run
+    Condvar {
+        waiters: VecDeque<(TaskID, SyncID)>,
+    },
+}
+
+impl SyncObject {
+    pub fn new(kind: usize, permits: usize) -> Option<Self> {
+        match kind {
+            SYNC_MUTEX => Some(SyncObject::Mutex {
+                owner: None,
+                waiters: VecDeque::new(),
+            }),
+            SYNC_SEMAPHORE => Some(SyncObject::Semaphore {
+                permits,
+                waiters: VecDeque::new(),
+            }),
+            SYNC_CONDVAR => Some(SyncObject::Condvar {
+                waiters: VecDeque::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The thread that has it locked if it's a mutex
+    pub fn owner(&self) -> Option<TaskID> {
+        match self {
+            SyncObject::Mutex { owner, .. } => *owner,
+            _ => None,
+        }
+    }
+
+    /// Takes a thread out of the wait queue, returns false if it wasn't waiting
+    pub fn remove_waiter(&mut self, id: TaskID) -> bool {
+        match self {
+            SyncObject::Mutex { waiters, .. } | SyncObject::Semaphore { waiters, .. } => {
+                let len = waiters.len();
+                waiters.retain(|waiter| *waiter != id);
+                waiters.len() != len
+            }
+            SyncObject::Condvar { waiters } => {
+                let len = waiters.len();
+                waiters.retain(|(waiter, _)| *waiter != id);
+                waiters.len() != len
+            }
+        }
+    }
+}
+
+/// A lock that puts threads waiting for it to sleep instead of spinning
+/// Unlocking hands it straight to the thread that has waited longest
+pub struct Mutex<T> {
+    id: SyncID,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            id: syscall::sync_create(SYNC_MUTEX, 0),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks until the mutex is this thread's
+    /// Panics if this thread has already locked it
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        match syscall::retry_pending(|| syscall::mutex_lock(self.id)) {
+            SYNC_DONE => MutexGuard::new(self),
+            _ => panic!("Mutex is already locked by this thread"),
+        }
+    }
+
+    /// No other thread can have it locked when it's borrowed mutably
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T> Drop for Mutex<T> {
+    fn drop(&mut self) {
+        syscall::sync_destroy(self.id);
+    }
+}
+
+/// The mutex is locked for as long as the guard lives
+/// The kernel only lets the thread that locked it unlock it, so the guard can't be sent to another
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+    // Makes the guard !Send
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+    fn new(mutex: &'a Mutex<T>) -> Self {
+        Self {
+            mutex,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+unsafe impl<T: Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        let result = syscall::mutex_unlock(self.mutex.id);
+        debug_assert_eq!(
+            result, SYNC_DONE,
+            "Mutex unlocked by a thread that doesn't own it"
+        );
+    }
+}
+
+/// Hands out a fixed number of permits, threads wanting one when there are none left sleep
+/// A released permit goes straight to the thread that has waited longest
+pub struct Semaphore {
+    id: SyncID,
+}
+
+impl Semaphore {
+    pub fn new(permits: usize) -> Self {
+        Self {
+            id: syscall::sync_create(SYNC_SEMAPHORE, permits),
+        }
+    }
+
+    /// Blocks until a permit is free and takes it
+    pub fn acquire(&self) {
+        syscall::retry_pending(|| syscall::semaphore_acquire(self.id));
+    }
+
+    /// Gives a permit back, it doesn't have to be one this thread acquired
+    pub fn release(&self) {
+        syscall::semaphore_release(self.id);
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        syscall::sync_destroy(self.id);
+    }
+}
+
+/// Lets threads sleep until another thread tells them something they are waiting for has changed
+/// Threads can wake up without being notified, so always check again after waiting
+pub struct Condvar {
+    id: SyncID,
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Self {
+            id: syscall::sync_create(SYNC_CONDVAR, 0),
+        }
+    }
+
+    /// Unlocks the guard's mutex and sleeps until notified, the mutex is locked again when it returns
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        // The kernel unlocks it and hands it back before the thread runs again
+        mem::forget(guard);
+
+        match syscall::condvar_wait(self.id, mutex.id) {
+            SYNC_DONE => MutexGuard::new(mutex),
+            // The none task can't sleep, it waits a tick and locks it again itself
+            PENDING => {
+                syscall::idle_wait();
+                mutex.lock()
+            }
+            _ => panic!("Condvar waited on with a mutex that isn't locked by this thread"),
+        }
+    }
+
+    /// Waits for as long as condition is true
+    pub fn wait_while<'a, T, F>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T>
+    where
+        F: FnMut(&mut T) -> bool,
+    {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes the thread that has waited longest
+    pub fn notify_one(&self) {
+        syscall::condvar_notify(self.id, 1);
+    }
+
+    pub fn notify_all(&self) {
+        syscall::condvar_notify(self.id, usize::MAX);
+    }
+}
+
+impl Default for Condvar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for Condvar {
+    fn drop(&mut self) {
+        syscall::sync_destroy(self.id);
+    }
+}
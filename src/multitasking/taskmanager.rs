@@ -1,62 +1,207 @@
-use core::ptr::write_volatile;
+use core::{mem, ptr::write_volatile, slice, str};
 
 use alloc::{
     boxed::Box,
-    collections::{BTreeMap, VecDeque},
-    sync::Arc,
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
 };
-use spin::Mutex;
-use x86_64::{VirtAddr, instructions::{hlt, interrupts::enable_and_hlt}, software_interrupt, structures::{idt::{InterruptStackFrame, InterruptStackFrameValue}, paging::OffsetPageTable}};
+use x86_64::{VirtAddr, instructions::{hlt, interrupts::enable_and_hlt}, software_interrupt, structures::{idt::{InterruptStackFrame, InterruptStackFrameValue, PageFaultErrorCode}, paging::{mapper::MapToError, PageTableFlags}}};
 
 use crate::{
-    assembly::registers::Registers, executor::task, memory::BootInfoFrameAllocator,
-    syscall::quit_function,
+    allocator::slab::SlabBox, assembly::registers::Registers, executor::task,
+    memory::{
+        address_space,
+        layout::{self, Region},
+        vma::LazyFault,
+    },
+    syscall::{
+        exit_thread, MemoryError, JOIN_NOT_FOUND, MAP_FILE, MAP_FIXED, MAP_POPULATE, PENDING,
+        PROT_WRITE,
+    },
 };
 
-use super::{Task, TaskID, TaskManager, TaskManagerInit, TASKMANAGER};
+use super::{
+    process::Process,
+    scheduler::{Priority, RunQueues},
+    stack::{Stack, StackError, DEFAULT_STACK_SIZE, MAX_STACK_SIZE, STACK_SLOT_SIZE},
+    sync::{SyncID, SyncObject, SYNC_DONE, SYNC_FAILED},
+    timer::{self, TimerWheel},
+    ExitStatus, ProcessID, Task, TaskID, TaskInfo, TaskManager, TaskManagerInit, TaskState,
+    TASKMANAGER, TASK_CACHE,
+};
+
+impl TaskManagerInit {
+    fn new_task(
+        &mut self,
+        stack_size: usize,
+        process: ProcessID,
+        priority: Priority,
+    ) -> Result<SlabBox<Task>, StackError> {
+        // Reuse the stack slot of a thread that has quit if there is one
+        let slot = match self.free_stacks.pop() {
+            Some(slot) => slot,
+            None => layout::allocate(Region::Stacks, STACK_SLOT_SIZE, 4096)
+                .ok_or(StackError::NoSlot)?
+                .as_u64(),
+        };
+
+        let stack = Stack::new(VirtAddr::new(slot), stack_size);
+        let err = match stack {
+            Ok(stack) => match TASK_CACHE.try_alloc(Task::new(stack, process, priority)) {
+                Ok(task) => return Ok(task),
+                Err(task) => {
+                    unsafe { task.stack.free() };
+                    StackError::Map(MapToError::FrameAllocationFailed)
+                }
+            },
+            Err(err) => err,
+        };
+
+        // Something else is using it, so it can't be handed out again
+        if let StackError::SlotInUse = err {
+            println!(
+                "WARNING: stack slot {:#x} is already in use, leaking it",
+                slot
+            );
+        } else {
+            self.free_stacks.push(slot);
+        }
+        Err(err)
+    }
+}
 
 impl TaskManager {
     pub fn new() -> Self {
         Self {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(Mutex::new(VecDeque::with_capacity(100))),
+            processes: BTreeMap::new(),
+            run_queues: RunQueues::new(),
             current_task: TaskID::none_task(),
+            idle_running: false,
+            timers: TimerWheel::new(),
+            exited: BTreeMap::new(),
+            joiners: BTreeMap::new(),
+            sync_objects: BTreeMap::new(),
+            dead_tasks: Vec::new(),
             dynamic: None,
         }
     }
 
-    pub fn init(
-        &mut self,
-        mut frame_allocator: BootInfoFrameAllocator,
-        mut mapper: OffsetPageTable<'static>,
-    ) {
+    pub fn init(&mut self) {
+        let mut dynamic = TaskManagerInit { free_stacks: Vec::new() };
+
+        // Everything started so far is part of the kernel
+        self.processes.insert(ProcessID::kernel(), Process::kernel());
+
         // Create a nop task which hlt's every time
-        let mut nop_task = Task::new(&mut frame_allocator, &mut mapper);
+        let mut nop_task = dynamic
+            .new_task(DEFAULT_STACK_SIZE, ProcessID::kernel(), Priority::Low)
+            .expect("Failed to create nop task stack");
         nop_task.id = TaskID::none_task();
+        nop_task.name = Some(String::from("idle"));
+        // Whatever booted the task manager counts as the idle task until the first switch
+        nop_task.state = TaskState::Running;
         nop_task.state_isf.instruction_pointer = VirtAddr::from_ptr(nop_function as *const usize);
 
         self.tasks.insert(TaskID::none_task(), nop_task);
 
-        self.dynamic = Some(TaskManagerInit {
-            frame_allocator,
-            mapper,
-        });
+        self.dynamic = Some(dynamic);
     }
 
-    pub fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
+    pub fn spawn(&mut self, task: SlabBox<Task>) {
+        let (task_id, priority) = (task.id, task.priority);
         if self.tasks.insert(task.id, task).is_some() {
             println!("Task with same ID already exists in tasks");
         }
-        self.task_queue.lock().push_back(task_id);
+        self.run_queues.push(task_id, priority, timer::ticks());
     }
 
     /// To be called from syscall
+    /// Runs the function in r8 with a stack of r9 bytes at the priority in r10
     pub fn spawn_thread_sys(&mut self, regs: &mut Registers) {
-        let mut task_queue = self.task_queue.lock();
+        // Free old stacks first so that they can be reused
+        self.free_dead_tasks();
+
+        // New threads belong to the same process as the thread that spawned them
+        let process = match self.tasks.get(&self.current_task) {
+            Some(task) => task.process,
+            None => ProcessID::kernel(),
+        };
+        let priority = Priority::from_usize(regs.r10).unwrap_or_default();
+        self.spawn_thread_in(regs, process, priority);
+    }
+
+    /// To be called from syscall
+    /// Creates a process with its own address space and runs the function as its first thread
+    pub fn spawn_process_sys(&mut self, regs: &mut Registers) {
+        self.free_dead_tasks();
+        self.start_process(regs, Process::new());
+    }
+
+    /// To be called from syscall
+    /// Like spawn_process_sys, but the process starts with a copy on write copy of the caller's memory
+    pub fn fork_sys(&mut self, regs: &mut Registers) {
+        self.free_dead_tasks();
+
+        let process = self
+            .processes
+            .get(&self.current_process())
+            .and_then(|parent| parent.fork());
+        self.start_process(regs, process);
+    }
+
+    // Runs the function in r8 as the first thread of process
+    // Sets rax to the new process's id, or the kernel's if it couldn't be started
+    fn start_process(&mut self, regs: &mut Registers, process: Option<Process>) {
+        // Return the kernel process if the process couldn't be created
+        regs.rax = ProcessID::kernel().0;
+
+        let process = match process {
+            Some(process) => process,
+            None => {
+                println!("Failed to allocate a page table, dropping new process");
+                return;
+            }
+        };
+        let process_id = process.id;
+        self.processes.insert(process_id, process);
+
+        self.spawn_thread_in(regs, process_id, Priority::default());
+        match self.tasks.get_mut(&TaskID::from(regs.rax)) {
+            Some(task) => {
+                // Nothing gets a handle to join it, so how it ends doesn't need keeping
+                task.detached = true;
+                regs.rax = process_id.0;
+            }
+            None => {
+                // Nothing will ever run in it
+                self.processes.remove(&process_id);
+                regs.rax = ProcessID::kernel().0;
+            }
+        }
+    }
+
+    /// Creates a thread in process from the function in r8 with a stack of r9 bytes
+    /// Sets rax to the new task's id
+    fn spawn_thread_in(&mut self, regs: &mut Registers, process: ProcessID, priority: Priority) {
+        // Return the none task if the thread couldn't be created
+        regs.rax = TaskID::none_task().0;
+
+        let stack_size = regs.r9;
+        if stack_size == 0 || stack_size > MAX_STACK_SIZE {
+            println!("Invalid stack size {}, dropping new thread", stack_size);
+            return;
+        }
 
         if let Some(dynamic) = &mut self.dynamic {
-            let mut task = Task::new(&mut dynamic.frame_allocator, &mut dynamic.mapper);
+            let mut task = match dynamic.new_task(stack_size, process, priority) {
+                Ok(task) => task,
+                Err(err) => {
+                    println!("Failed to create thread stack: {:?}, dropping new thread", err);
+                    return;
+                }
+            };
             let task_id = task.id;
 
             // Return task id as successfull result
@@ -68,10 +213,14 @@ impl TaskManager {
             // Pass function to first param
             task.state_reg.rdi = regs.r8;
 
+            if let Some(process) = self.processes.get_mut(&process) {
+                process.threads += 1;
+            }
+
             if self.tasks.insert(task.id, task).is_some() {
                 println!("Task with same ID already exists in tasks");
             }
-            task_queue.push_back(task_id);
+            self.run_queues.push(task_id, priority, timer::ticks());
         } else {
             println!("TaskManager not initialized, dropping new thread");
         }
@@ -84,12 +233,498 @@ impl TaskManager {
     //     task.func.clone()
     // }
 
+    /// To be called from syscall
+    /// Ends the current thread with the exit status in r8
     pub fn quit(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
-        self.tasks.remove(&self.current_task);
+        let status = ExitStatus::from_usize(regs.r8).unwrap_or(ExitStatus::Exited);
+        self.exit_current(stack_frame, regs, status)
+    }
+
+    /// To be called from syscall
+    /// Ends thread r8 wherever it is, rax is 1 if there was such a thread
+    /// Anything it owned is leaked and any spin locks it held stay locked
+    pub fn kill_sys(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+        let target = TaskID::from(regs.r8);
+        regs.rax = 0;
+
+        // The idle task has to be there for when nothing else is ready
+        if target.is_none() {
+            return;
+        }
+        if target == self.current_task {
+            return self.exit_current(stack_frame, regs, ExitStatus::Killed);
+        }
+
+        let task = match self.tasks.remove(&target) {
+            Some(task) => task,
+            None => return,
+        };
+        // Whichever queue it's in it won't run again
+        self.run_queues.remove(target);
+        self.timers.remove(target);
+        self.joiners.retain(|_, joiner| *joiner != target);
+
+        self.finish(target, task.detached, ExitStatus::Killed);
+        regs.rax = 1;
+
+        // It isn't running so its stack can go straight away
+        self.dead_tasks.push(task);
+        self.free_dead_tasks();
+    }
+
+    /// Ends the running thread with status and switches to the next one
+    pub(crate) fn exit_current(
+        &mut self,
+        stack_frame: &mut InterruptStackFrame,
+        regs: &mut Registers,
+        status: ExitStatus,
+    ) {
+        if self.current_task.is_none() {
+            println!("WARNING: the none task can't quit");
+            return;
+        }
+
+        // Any previously quit tasks are no longer running so they can be freed
+        self.free_dead_tasks();
+
+        // We are still running on this task's stack
+        // So it can only be freed once another task has taken over
+        if let Some(task) = self.tasks.remove(&self.current_task) {
+            self.finish(task.id, task.detached, status);
+            self.dead_tasks.push(task);
+        }
         self.current_task = TaskID::none_task();
 
         // Switch to next task
-        self.switch_task_interrupt(stack_frame, regs)
+        self.switch_task(stack_frame, regs)
+    }
+
+    // Hands how a thread ended to the thread joining it, or keeps it until it is joined
+    // Also takes it out of every wait queue and hands on the mutexes it held
+    fn finish(&mut self, task_id: TaskID, detached: bool, status: ExitStatus) {
+        match self.joiners.remove(&task_id) {
+            Some(joiner) => self.wake(joiner, status as usize),
+            None if !detached => {
+                self.exited.insert(task_id, status);
+            }
+            None => {}
+        }
+
+        let mut held = Vec::new();
+        for (id, object) in self.sync_objects.iter_mut() {
+            object.remove_waiter(task_id);
+            if object.owner() == Some(task_id) {
+                held.push(*id);
+            }
+        }
+        for id in held {
+            self.hand_off_mutex(id);
+        }
+    }
+
+    // Puts a blocked thread back in the run queues, result is what its syscall returns
+    fn wake(&mut self, task_id: TaskID, result: usize) {
+        if let Some(task) = self.tasks.get_mut(&task_id) {
+            task.state_reg.rax = result;
+            task.state = TaskState::Ready;
+            let priority = task.priority;
+            self.run_queues.push(task_id, priority, timer::ticks());
+        }
+    }
+
+    /// To be called from syscall
+    /// Waits for thread r8 to end, setting rax to its exit status
+    /// The none task can't wait, it gets PENDING back if the thread is still running
+    pub fn join_sys(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+        let target = TaskID::from(regs.r8);
+        if let Some(status) = self.exited.remove(&target) {
+            regs.rax = status as usize;
+            return;
+        }
+
+        // A thread can't wait for itself or the idle task, and only one thread can wait for each
+        let joinable = match self.tasks.get(&target) {
+            Some(task) => {
+                !task.detached
+                    && target != self.current_task
+                    && !target.is_none()
+                    && !self.joiners.contains_key(&target)
+            }
+            None => false,
+        };
+        if !joinable {
+            regs.rax = JOIN_NOT_FOUND;
+        } else if self.current_task.is_none() {
+            regs.rax = PENDING;
+        } else {
+            self.joiners.insert(target, self.current_task);
+            self.block_current(stack_frame, regs, TaskState::Blocked);
+        }
+    }
+
+    /// To be called from syscall
+    /// Stops keeping how thread r8 ended, rax is 1 if there was such a thread
+    pub fn detach_sys(&mut self, regs: &mut Registers) {
+        let target = TaskID::from(regs.r8);
+        regs.rax = 0;
+
+        if self.exited.remove(&target).is_some() {
+            regs.rax = 1;
+        } else if let Some(task) = self.tasks.get_mut(&target) {
+            if !target.is_none() {
+                task.detached = true;
+                regs.rax = 1;
+            }
+        }
+    }
+
+    // Takes the current thread off the CPU without queueing it, state is what it's waiting in
+    // Whatever it's waiting for has to put it back in the run queues
+    fn block_current(
+        &mut self,
+        stack_frame: &mut InterruptStackFrame,
+        regs: &mut Registers,
+        state: TaskState,
+    ) {
+        let task = self.tasks.get_mut(&self.current_task).unwrap();
+        task.save(stack_frame, regs);
+        task.state = state;
+
+        // Nothing else may be ready, the none task waits for the timer then
+        let next_task = self.run_queues.pop().unwrap_or(TaskID::none_task());
+        self.current_task = next_task;
+        unsafe { self.set_registers(stack_frame, regs, next_task) }
+    }
+
+    /// To be called from syscall
+    /// Creates a mutex, semaphore or condvar of kind r8, a semaphore starts with r9 permits
+    /// Sets rax to its id, 0 if there is no such kind
+    pub fn sync_create_sys(&mut self, regs: &mut Registers) {
+        regs.rax = match SyncObject::new(regs.r8, regs.r9) {
+            Some(object) => {
+                let id = SyncID::new();
+                self.sync_objects.insert(id, object);
+                id.into()
+            }
+            None => 0,
+        };
+    }
+
+    /// To be called from syscall
+    /// Frees sync object r8, anything still waiting on it stays blocked
+    pub fn sync_destroy_sys(&mut self, regs: &mut Registers) {
+        regs.rax = match self.sync_objects.remove(&SyncID::from(regs.r8)) {
+            Some(_) => SYNC_DONE,
+            None => SYNC_FAILED,
+        };
+    }
+
+    /// To be called from syscall
+    /// Locks mutex r8, blocking until it's handed over if another thread has it
+    /// Fails if the caller already has it, the none task gets PENDING back instead of blocking
+    pub fn mutex_lock_sys(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+        let current = self.current_task;
+        let (owner, waiters) = match self.sync_objects.get_mut(&SyncID::from(regs.r8)) {
+            Some(SyncObject::Mutex { owner, waiters }) => (owner, waiters),
+            _ => {
+                regs.rax = SYNC_FAILED;
+                return;
+            }
+        };
+
+        regs.rax = match *owner {
+            None => {
+                *owner = Some(current);
+                SYNC_DONE
+            }
+            // It would wait for itself forever
+            Some(owner) if owner == current => SYNC_FAILED,
+            Some(_) if current.is_none() => PENDING,
+            Some(_) => {
+                waiters.push_back(current);
+                return self.block_current(stack_frame, regs, TaskState::Blocked);
+            }
+        };
+    }
+
+    /// To be called from syscall
+    /// Unlocks mutex r8, which the caller has to have locked
+    pub fn mutex_unlock_sys(&mut self, regs: &mut Registers) {
+        let id = SyncID::from(regs.r8);
+        let owner = self.sync_objects.get(&id).and_then(SyncObject::owner);
+        regs.rax = if owner == Some(self.current_task) {
+            self.hand_off_mutex(id);
+            SYNC_DONE
+        } else {
+            SYNC_FAILED
+        };
+    }
+
+    // Gives a mutex to the thread that has waited longest for it, or unlocks it if nothing is waiting
+    // The next owner gets it straight away so a thread that hasn't waited can't take it first
+    fn hand_off_mutex(&mut self, id: SyncID) {
+        let next = match self.sync_objects.get_mut(&id) {
+            Some(SyncObject::Mutex { owner, waiters }) => {
+                *owner = waiters.pop_front();
+                *owner
+            }
+            _ => None,
+        };
+        if let Some(next) = next {
+            self.wake(next, SYNC_DONE);
+        }
+    }
+
+    /// To be called from syscall
+    /// Takes a permit from semaphore r8, blocking until one is released if there are none
+    /// The none task gets PENDING back instead of blocking
+    pub fn semaphore_acquire_sys(
+        &mut self,
+        stack_frame: &mut InterruptStackFrame,
+        regs: &mut Registers,
+    ) {
+        let current = self.current_task;
+        let (permits, waiters) = match self.sync_objects.get_mut(&SyncID::from(regs.r8)) {
+            Some(SyncObject::Semaphore { permits, waiters }) => (permits, waiters),
+            _ => {
+                regs.rax = SYNC_FAILED;
+                return;
+            }
+        };
+
+        regs.rax = if *permits > 0 {
+            *permits -= 1;
+            SYNC_DONE
+        } else if current.is_none() {
+            PENDING
+        } else {
+            waiters.push_back(current);
+            return self.block_current(stack_frame, regs, TaskState::Blocked);
+        };
+    }
+
+    /// To be called from syscall
+    /// Gives the permit to the thread that has waited longest on semaphore r8, or back to the semaphore
+    pub fn semaphore_release_sys(&mut self, regs: &mut Registers) {
+        let next = match self.sync_objects.get_mut(&SyncID::from(regs.r8)) {
+            Some(SyncObject::Semaphore { permits, waiters }) => match waiters.pop_front() {
+                Some(next) => Some(next),
+                None => {
+                    *permits += 1;
+                    None
+                }
+            },
+            _ => {
+                regs.rax = SYNC_FAILED;
+                return;
+            }
+        };
+
+        if let Some(next) = next {
+            self.wake(next, SYNC_DONE);
+        }
+        regs.rax = SYNC_DONE;
+    }
+
+    /// To be called from syscall
+    /// Unlocks mutex r9 and blocks on condvar r8, the mutex is the caller's again when it runs
+    /// The none task can't block, it gets PENDING back with the mutex unlocked
+    pub fn condvar_wait_sys(
+        &mut self,
+        stack_frame: &mut InterruptStackFrame,
+        regs: &mut Registers,
+    ) {
+        let (condvar, mutex) = (SyncID::from(regs.r8), SyncID::from(regs.r9));
+        let owner = self.sync_objects.get(&mutex).and_then(SyncObject::owner);
+        let owns_mutex = owner == Some(self.current_task);
+        let is_condvar = matches!(
+            self.sync_objects.get(&condvar),
+            Some(SyncObject::Condvar { .. })
+        );
+        if !owns_mutex || !is_condvar {
+            regs.rax = SYNC_FAILED;
+            return;
+        }
+
+        self.hand_off_mutex(mutex);
+        if self.current_task.is_none() {
+            regs.rax = PENDING;
+            return;
+        }
+
+        if let Some(SyncObject::Condvar { waiters }) = self.sync_objects.get_mut(&condvar) {
+            waiters.push_back((self.current_task, mutex));
+        }
+        self.block_current(stack_frame, regs, TaskState::Blocked)
+    }
+
+    /// To be called from syscall
+    /// Wakes up to r9 of the threads waiting on condvar r8, rax is how many were woken
+    /// Each only runs once it has its mutex back
+    pub fn condvar_notify_sys(&mut self, regs: &mut Registers) {
+        let condvar = SyncID::from(regs.r8);
+        let mut woken = 0;
+        while woken < regs.r9 {
+            let (task_id, mutex) = match self.sync_objects.get_mut(&condvar) {
+                Some(SyncObject::Condvar { waiters }) => match waiters.pop_front() {
+                    Some(waiter) => waiter,
+                    None => break,
+                },
+                _ => break,
+            };
+            woken += 1;
+
+            // Straight from waiting on the condvar to waiting on the mutex
+            match self.sync_objects.get_mut(&mutex) {
+                Some(SyncObject::Mutex { owner, waiters }) => match *owner {
+                    Some(_) => waiters.push_back(task_id),
+                    None => {
+                        *owner = Some(task_id);
+                        self.wake(task_id, SYNC_DONE);
+                    }
+                },
+                // The mutex is gone, it can't be given back
+                _ => self.wake(task_id, SYNC_FAILED),
+            }
+        }
+        regs.rax = woken;
+    }
+
+    /// Unmaps the stacks of quit tasks and keeps their slots for new threads
+    fn free_dead_tasks(&mut self) {
+        if let Some(dynamic) = &mut self.dynamic {
+            for task in self.dead_tasks.drain(..) {
+                unsafe { task.stack.free() };
+                dynamic.free_stacks.push(task.stack.slot().as_u64());
+
+                let last_thread = match self.processes.get_mut(&task.process) {
+                    Some(process) => {
+                        process.threads -= 1;
+                        process.threads == 0 && !process.id.is_kernel()
+                    }
+                    None => false,
+                };
+                if last_thread {
+                    // Frees the process's address space too
+                    self.processes.remove(&task.process);
+                }
+            }
+        }
+    }
+
+    /// How many threads have ended without being joined or detached yet
+    pub fn unjoined_count(&self) -> usize {
+        self.exited.len()
+    }
+
+    /// If addr lies below the current task's stack returns the current task
+    pub fn stack_overflowed(&self, addr: VirtAddr) -> Option<TaskID> {
+        if self.current_task.is_none() {
+            return None;
+        }
+
+        match self.tasks.get(&self.current_task) {
+            Some(task) if task.stack.is_overflow(addr) => Some(self.current_task),
+            _ => None,
+        }
+    }
+
+    /// The process of the running task, the kernel if nothing is running
+    fn current_process(&self) -> ProcessID {
+        match self.tasks.get(&self.current_task) {
+            Some(task) => task.process,
+            None => ProcessID::kernel(),
+        }
+    }
+
+    /// Handles a page fault in the current process's region
+    pub fn handle_process_fault(
+        &self,
+        addr: VirtAddr,
+        error_code: PageFaultErrorCode,
+    ) -> LazyFault {
+        match self.processes.get(&self.current_process()) {
+            Some(process) => process.address_space().handle_fault(addr, error_code),
+            None => LazyFault::NotReserved,
+        }
+    }
+
+    /// Writes up to count cold pages of the current process out to swap
+    /// Returns how many pages were swapped out
+    pub fn swap_out(&self, count: usize) -> usize {
+        match self.processes.get(&self.current_process()) {
+            Some(process) => process.address_space().swap_out(count),
+            None => 0,
+        }
+    }
+
+    /// To be called from syscall
+    /// Reserves r9 bytes in the caller's process, at r8 if MAP_FIXED is set in r10
+    pub fn mmap_sys(&mut self, regs: &mut Registers) {
+        let flags = regs.r10;
+        let result = if flags & MAP_FILE != 0 {
+            Err(MemoryError::Unsupported)
+        } else {
+            let addr = match flags & MAP_FIXED {
+                0 => None,
+                _ => VirtAddr::try_new(regs.r8 as u64).ok(),
+            };
+
+            if flags & MAP_FIXED != 0 && addr.is_none() {
+                Err(MemoryError::InvalidRange)
+            } else {
+                let process = self.current_process();
+                match self.processes.get_mut(&process) {
+                    Some(process) => process
+                        .address_space_mut()
+                        .map(addr, regs.r9 as u64, page_flags(flags), flags & MAP_POPULATE != 0)
+                        .map_err(MemoryError::from),
+                    None => Err(MemoryError::InvalidRange),
+                }
+            }
+        };
+
+        regs.rax = match result {
+            Ok(addr) => addr.as_u64() as usize,
+            Err(err) => err.code(),
+        };
+    }
+
+    /// To be called from syscall
+    /// Unmaps r9 bytes at r8 in the caller's process
+    pub fn munmap_sys(&mut self, regs: &mut Registers) {
+        let process = self.current_process();
+        let result = match (self.processes.get_mut(&process), VirtAddr::try_new(regs.r8 as u64)) {
+            (Some(process), Ok(addr)) => process
+                .address_space_mut()
+                .unmap(addr, regs.r9 as u64)
+                .map_err(MemoryError::from),
+            _ => Err(MemoryError::InvalidRange),
+        };
+
+        regs.rax = match result {
+            Ok(()) => 0,
+            Err(err) => err.code(),
+        };
+    }
+
+    /// To be called from syscall
+    /// Changes the protection of r9 bytes at r8 in the caller's process to r10
+    pub fn mprotect_sys(&mut self, regs: &mut Registers) {
+        let process = self.current_process();
+        let flags = page_flags(regs.r10);
+        let result = match (self.processes.get_mut(&process), VirtAddr::try_new(regs.r8 as u64)) {
+            (Some(process), Ok(addr)) => process
+                .address_space_mut()
+                .protect(addr, regs.r9 as u64, flags)
+                .map_err(MemoryError::from),
+            _ => Err(MemoryError::InvalidRange),
+        };
+
+        regs.rax = match result {
+            Ok(()) => 0,
+            Err(err) => err.code(),
+        };
     }
 
     unsafe fn set_registers(
@@ -98,8 +733,22 @@ impl TaskManager {
         regs: &mut Registers,
         task_id: TaskID,
     ) {
+        self.idle_running = task_id.is_none();
+
+        // The idle task is never queued, it's ready whenever something else is running
+        if let Some(idle) = self.tasks.get_mut(&TaskID::none_task()) {
+            idle.state = TaskState::Ready;
+        }
+
         // Get the new task's task data
         let task = self.tasks.get_mut(&task_id).unwrap();
+        task.state = TaskState::Running;
+
+        // Switch page tables if the new task is in a different process
+        match self.processes.get(&task.process) {
+            Some(process) => process.address_space().activate(),
+            None => println!("WARNING: task {:?} has no process", task_id),
+        }
 
         // Write the new tasks stack frame
 
@@ -112,31 +761,123 @@ impl TaskManager {
         write_volatile(regs, task.state_reg.clone());
     }
 
-    pub fn yield_now(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
-        let mut task_queue = self.task_queue.lock();
-        task_queue.push_back(self.current_task);
+    /// To be called from syscall
+    /// Sets the priority of thread r8, or the caller if it is 0, to r9
+    /// rax is 1 if it was changed
+    pub fn set_priority_sys(&mut self, regs: &mut Registers) {
+        regs.rax = 0;
+
+        let task_id = match TaskID::from(regs.r8) {
+            id if id.is_none() => self.current_task,
+            id => id,
+        };
+        let priority = match Priority::from_usize(regs.r9) {
+            Some(priority) if !task_id.is_none() => priority,
+            // The idle task always stays at the bottom
+            _ => return,
+        };
+        if let Some(task) = self.tasks.get_mut(&task_id) {
+            task.priority = priority;
+            // Move it to its new queue if it is waiting to run
+            if self.run_queues.remove(task_id) {
+                self.run_queues.push(task_id, priority, timer::ticks());
+            }
+            regs.rax = 1;
+        }
+    }
 
-        if let Some(mut next_task) = task_queue.pop_front() {
-            // Save current task
-            self.tasks
-                .get_mut(&self.current_task)
-                .unwrap()
-                .save(stack_frame, regs);
+    /// To be called from syscall
+    /// Takes the caller off the run queues for r8 ticks, the none task can't sleep
+    pub fn sleep_sys(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+        let ticks = regs.r8 as u64;
+        if self.current_task.is_none() {
+            return;
+        }
+        if ticks == 0 {
+            return self.yield_now(stack_frame, regs);
+        }
 
-            // Since they yielded if we get the task again
-            // Execute none task
-            if self.current_task == next_task {
-                next_task = TaskID::none_task();
-                // Try again next tick
-                task_queue.push_back(self.current_task);
-            }
+        // A long enough sleep never ends rather than overflowing
+        let deadline = timer::ticks().saturating_add(ticks);
+        self.timers.insert(self.current_task, deadline);
+        self.block_current(stack_frame, regs, TaskState::Sleeping)
+    }
+
+    /// To be called from syscall
+    /// Copies a TaskInfo for each thread, up to r9 of them, to r8
+    /// Sets rax to how many threads there are, which can be more than were copied
+    /// Nothing is copied if r9 is 0, rax is 0 if the buffer isn't mapped and writable
+    pub fn task_list_sys(&mut self, regs: &mut Registers) {
+        regs.rax = self.tasks.len();
+        if regs.r9 == 0 {
+            return;
+        }
+
+        // An overflowing size becomes 0, which is rejected
+        let size = regs.r9.checked_mul(mem::size_of::<TaskInfo>()).unwrap_or(0);
+        let aligned = regs.r8 % mem::align_of::<TaskInfo>() == 0;
+        if !aligned || !address_space::is_range_mapped(regs.r8 as u64, size as u64, true) {
+            regs.rax = 0;
+            return;
+        }
+
+        let buffer = unsafe { slice::from_raw_parts_mut(regs.r8 as *mut TaskInfo, regs.r9) };
+        for (info, task) in buffer.iter_mut().zip(self.tasks.values()) {
+            *info = TaskInfo::new(task);
+        }
+    }
 
-            self.current_task = next_task;
+    /// To be called from syscall
+    /// Names thread r8, or the caller if it is 0, with the r10 bytes of UTF-8 at r9
+    /// rax is 1 if it was named
+    pub fn set_name_sys(&mut self, regs: &mut Registers) {
+        regs.rax = 0;
+
+        let task_id = match TaskID::from(regs.r8) {
+            id if id.is_none() => self.current_task,
+            id => id,
+        };
+        if !address_space::is_range_mapped(regs.r9 as u64, regs.r10 as u64, false) {
+            return;
+        }
+        let bytes = unsafe { slice::from_raw_parts(regs.r9 as *const u8, regs.r10) };
+        if let (Some(task), Ok(name)) = (self.tasks.get_mut(&task_id), str::from_utf8(bytes)) {
+            task.name = Some(name.to_string());
+            regs.rax = 1;
+        }
+    }
 
-            drop(task_queue);
+    /// The thread the CPU is running, the none task when idle
+    pub fn current_task(&self) -> TaskID {
+        self.current_task
+    }
 
-            unsafe { self.set_registers(stack_frame, regs, next_task) }
+    /// Gives the rest of the timeslice to any other ready thread, whatever its priority
+    /// When the none task yields it runs again once nothing else is ready
+    pub fn yield_now(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+        // Save current task
+        let task = self.tasks.get_mut(&self.current_task).unwrap();
+        task.save(stack_frame, regs);
+        task.state = TaskState::Ready;
+        let priority = task.priority;
+
+        let next_task = match self.run_queues.pop() {
+            Some(next_task) => next_task,
+            // Since they yielded if nothing else is ready
+            // Execute none task and try again next tick
+            None if !self.current_task.is_none() => TaskID::none_task(),
+            None => {
+                task.state = TaskState::Running;
+                return;
+            }
+        };
+        if !self.current_task.is_none() {
+            self.run_queues
+                .push(self.current_task, priority, timer::ticks());
         }
+
+        self.current_task = next_task;
+        unsafe { self.set_registers(stack_frame, regs, next_task) }
     }
 
     pub fn switch_task_interrupt(
@@ -144,36 +885,76 @@ impl TaskManager {
         stack_frame: &mut InterruptStackFrame,
         regs: &mut Registers,
     ) {
-        let mut task_queue = self.task_queue.lock();
+        let now = timer::tick();
+        self.run_queues.age(now);
 
-        // If task is none don't save
-        if !self.current_task.is_none() {
-            self.tasks
-                .get_mut(&self.current_task)
-                .unwrap()
-                .save(stack_frame, regs);
+        // The tick goes to whatever was running, the idle task's are the CPU's idle time
+        if let Some(task) = self.tasks.get_mut(&self.current_task) {
+            task.ticks += 1;
+        }
+
+        // Threads whose sleep is over are ready again
+        let (tasks, run_queues) = (&mut self.tasks, &mut self.run_queues);
+        self.timers.expire(now, |task_id| {
+            if let Some(task) = tasks.get_mut(&task_id) {
+                task.state = TaskState::Ready;
+                run_queues.push(task_id, task.priority, now);
+            }
+        });
+
+        self.switch_task(stack_frame, regs)
+    }
 
-            // Push the current task to the back of the queue
-            task_queue.push_back(self.current_task);
+    // Puts the current task back in its queue and runs the highest priority ready task
+    fn switch_task(&mut self, stack_frame: &mut InterruptStackFrame, regs: &mut Registers) {
+        let now = timer::ticks();
+        if !self.current_task.is_none() {
+            let task = self.tasks.get_mut(&self.current_task).unwrap();
+            task.save(stack_frame, regs);
+            task.state = TaskState::Ready;
+
+            // Back to its own priority's queue, even if it had aged into a higher one
+            let priority = task.priority;
+            self.run_queues.push(self.current_task, priority, now);
+        } else if self.idle_running {
+            // Save the none task so it carries on where it was, it is never queued
+            // Before the first switch it is whatever booted the task manager, which shouldn't come back
+            if let Some(task) = self.tasks.get_mut(&TaskID::none_task()) {
+                task.save(stack_frame, regs);
+            }
         }
 
         // Can we get a new task from the queue
-        if let Some(next_task_id) = task_queue.pop_front() {
+        if let Some(next_task_id) = self.run_queues.pop() {
             // If we got the same task as before keep running it
             if self.current_task == next_task_id {
+                if let Some(task) = self.tasks.get_mut(&next_task_id) {
+                    task.state = TaskState::Running;
+                }
                 return;
             }
 
             // Set current task to our new task
             self.current_task = next_task_id;
 
-            drop(task_queue);
-
             unsafe { self.set_registers(stack_frame, regs, next_task_id) };
+        } else if self.current_task.is_none() {
+            // Nothing is ready (e.g. the last thread quit) so run the nop task
+            unsafe { self.set_registers(stack_frame, regs, TaskID::none_task()) };
         }
     }
 }
 
+// Page table flags for the PROT_* bits of mmap and mprotect
+// Process memory is never executable
+fn page_flags(flags: usize) -> PageTableFlags {
+    let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE;
+    if flags & PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    page_flags
+}
+
 // pub fn spawn_thread<F>(func: F)
 // where
 //     F: Fn() + Send + Sync + 'static,
@@ -202,5 +983,5 @@ extern "C" fn thread_bootstraper(main: *mut usize) {
     func.call_once(());
 
     // Function ended quit
-    quit_function()
+    exit_thread(ExitStatus::Exited)
 }
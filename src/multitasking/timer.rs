@@ -0,0 +1,82 @@
+use core::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use alloc::vec::Vec;
+
+use crate::interrupts::hardware::TIMER_FREQUENCY;
+
+use super::TaskID;
+
+// Slots in the wheel, a deadline further away than this waits for the wheel to come round again
+const WHEEL_SIZE: usize = 64;
+
+// Timer interrupts since boot
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Timer ticks since boot, there are TIMER_FREQUENCY of them a second
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Counts a timer interrupt, returning the new tick
+pub(super) fn tick() -> u64 {
+    TICKS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+/// How many ticks a duration lasts, rounded up so a sleep is never cut short
+/// Durations too long to count in ticks give u64::MAX
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos() * TIMER_FREQUENCY as u128;
+    let ticks = (nanos + 999_999_999) / 1_000_000_000;
+    ticks.min(u64::MAX as u128) as u64
+}
+
+struct Sleeper {
+    id: TaskID,
+    deadline: u64,
+}
+
+/// Sleeping threads, hashed into slots by the tick they wake on
+/// Each tick only the slot for that tick has to be looked at
+pub(super) struct TimerWheel {
+    slots: Vec<Vec<Sleeper>>,
+}
+
+impl TimerWheel {
+    pub fn new() -> Self {
+        Self {
+            slots: (0..WHEEL_SIZE).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    pub fn insert(&mut self, id: TaskID, deadline: u64) {
+        self.slots[deadline as usize % WHEEL_SIZE].push(Sleeper { id, deadline });
+    }
+
+    /// Takes a thread out of the wheel before its deadline
+    pub fn remove(&mut self, id: TaskID) -> bool {
+        for slot in self.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|sleeper| sleeper.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Calls wake for every thread whose deadline is now
+    /// Doesn't allocate, it's called from the timer interrupt
+    pub fn expire(&mut self, now: u64, mut wake: impl FnMut(TaskID)) {
+        let slot = &mut self.slots[now as usize % WHEEL_SIZE];
+        let mut index = 0;
+        while index < slot.len() {
+            if slot[index].deadline <= now {
+                wake(slot.swap_remove(index).id);
+            } else {
+                index += 1;
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
 use core::convert::TryInto;
 
 use spin::Mutex;
-use x86_64::instructions::port::Port;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
 use crate::executor::spawner::Spawner;
 
@@ -11,17 +11,22 @@ pub enum BaseAddressRegisterType {
     InputOutput = 1,
 }
 
-#[allow(dead_code)]
 pub struct BaseAddressRegister {
-    prefetchable: bool,
-    address: u8,
-    size: u32,
-    register_type: BaseAddressRegisterType,
+    pub prefetchable: bool,
+    // Physical address for memory mappings, port number for IO
+    pub address: u64,
+    pub size: u64,
+    pub register_type: BaseAddressRegisterType,
+    // A 64 bit memory BAR also takes up the register after it
+    pub is_64_bit: bool,
 }
 
 #[allow(dead_code)]
 pub struct PCIDevice {
     port_base: u32,
+    // The first memory mapped BAR, for drivers to pass to memory::mmio::map_mmio
+    mmio_base: u64,
+    mmio_size: u64,
     interrupt: u32,
 
     bus: u16,
@@ -114,6 +119,8 @@ impl PCI {
         // Why do C people overflow their numbers :/
         PCIDevice {
             port_base: 0,
+            mmio_base: 0,
+            mmio_size: 0,
             bus: bus,
             device: device,
             function: function,
@@ -198,35 +205,76 @@ impl PCI {
             return None;
         }
 
-        let bar_value = self.read(bus, device, function, 0x10 + 4 * bar_num as u32);
+        let offset = 0x10 + 4 * bar_num as u32;
+        let bar_value = self.read(bus, device, function, offset);
         let register_type = if bar_value & 0x1 == 1 {
             BaseAddressRegisterType::InputOutput
         } else {
             BaseAddressRegisterType::MemoryMapping
         };
 
-        let address;
-        let prefectchable;
-        if register_type == BaseAddressRegisterType::MemoryMapping {
-            prefectchable = ((bar_value >> 3) & 0x1) == 0x1;
-            address = 0;
-            // match (bar_value >> 1) & 0x3 {
-            //     0 => // 32 Bit Mode
-            //     1 => // 20 Bit mode
-            //     2 => // 64 Bit Mode
-            // }
-            // return None;
-        } else {
-            address = ((bar_value & !0x3) & 0xFF).try_into().unwrap();
-            prefectchable = false;
-        }
+        // Nothing else may run while the device has decoding off or a BAR holds the probe value
+        let bar = without_interrupts(|| {
+            // Decoding is turned off while probing so the device doesn't answer at the probed address
+            // Only the low 16 bits are written back, writing 1s to the status register clears it
+            let command = self.read(bus, device, function, 0x04) & 0xFFFF;
+            self.write(bus, device, function, 0x04, command & !0x3);
+
+            let bar = if register_type == BaseAddressRegisterType::MemoryMapping {
+                let prefetchable = ((bar_value >> 3) & 0x1) == 0x1;
+                // 0 is 32 bit, 1 is 20 bit (below 1 MiB), 2 is 64 bit
+                let is_64_bit = (bar_value >> 1) & 0x3 == 2 && bar_num + 1 < max_bars as u16;
+
+                let mut address = u64::from(bar_value & !0xF);
+                let mut mask = u64::from(self.probe(bus, device, function, offset) & !0xF);
+                if is_64_bit {
+                    let high = self.read(bus, device, function, offset + 4);
+                    address |= u64::from(high) << 32;
+                    mask |= u64::from(self.probe(bus, device, function, offset + 4)) << 32;
+                } else {
+                    // The upper half of the size is all 1s for a 32 bit BAR
+                    mask |= 0xFFFF_FFFF << 32;
+                }
+
+                // Nothing sticks if the BAR isn't implemented
+                let unimplemented = mask == 0 || mask == 0xFFFF_FFFF << 32;
+                BaseAddressRegister {
+                    prefetchable,
+                    address,
+                    size: if unimplemented {
+                        0
+                    } else {
+                        (!mask).wrapping_add(1)
+                    },
+                    register_type,
+                    is_64_bit,
+                }
+            } else {
+                // IO space is only 16 bits wide, the upper bits of the mask may read as 0
+                let mask = self.probe(bus, device, function, offset) & !0x3;
+                BaseAddressRegister {
+                    prefetchable: false,
+                    address: u64::from(bar_value & !0x3),
+                    size: u64::from((!mask).wrapping_add(1) & 0xFFFF),
+                    register_type,
+                    is_64_bit: false,
+                }
+            };
+
+            self.write(bus, device, function, 0x04, command);
+            bar
+        });
+        Some(bar)
+    }
 
-        Some(BaseAddressRegister {
-            prefetchable: prefectchable,
-            address: address,
-            size: 0,
-            register_type: register_type,
-        })
+    // Writes all 1s to a BAR to find out which address bits are fixed by its size
+    // Returns what was read back and restores the original value
+    fn probe(&mut self, bus: u16, device: u16, function: u16, offset: u32) -> u32 {
+        let original = self.read(bus, device, function, offset);
+        self.write(bus, device, function, offset, 0xFFFF_FFFF);
+        let mask = self.read(bus, device, function, offset);
+        self.write(bus, device, function, offset, original);
+        mask
     }
 
     pub fn select_drivers(&mut self) {
@@ -244,19 +292,29 @@ impl PCI {
                         continue;
                     }
 
-                    for bar_num in 0..6 {
+                    let mut bar_num = 0;
+                    while bar_num < 6 {
                         let bar =
                             match self.get_base_address_register(bus, device, function, bar_num) {
                                 Some(bar) => bar,
-                                None => continue,
+                                None => break,
                             };
+                        // The upper half of a 64 bit BAR isn't a BAR of its own
+                        bar_num += if bar.is_64_bit { 2 } else { 1 };
 
                         // Ensure there is an address
-                        // We currently only have support for IO not mmap
-                        if bar.address != 0
-                            && (bar.register_type == BaseAddressRegisterType::InputOutput)
-                        {
-                            dev.port_base = bar.address.into();
+                        if bar.address == 0 || bar.size == 0 {
+                            continue;
+                        }
+                        match bar.register_type {
+                            BaseAddressRegisterType::InputOutput if dev.port_base == 0 => {
+                                dev.port_base = bar.address as u32;
+                            }
+                            BaseAddressRegisterType::MemoryMapping if dev.mmio_base == 0 => {
+                                dev.mmio_base = bar.address;
+                                dev.mmio_size = bar.size;
+                            }
+                            _ => {}
                         }
 
                         // if driver != 0 {
@@ -275,6 +333,9 @@ impl PCI {
                         dev.device_id & 0xFF
                     );
                     self.get_device_name(&mut dev);
+                    if dev.mmio_base != 0 {
+                        println!("MMIO: {:#X} ({} KiB)", dev.mmio_base, dev.mmio_size / 1024);
+                    }
                 }
             }
         }
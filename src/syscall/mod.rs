@@ -1,16 +1,110 @@
-use alloc::boxed::Box;
+use core::time::Duration;
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use spin::Mutex;
 use x86_64::{
-    instructions::interrupts::without_interrupts,
+    instructions::interrupts::{self, enable_and_hlt, without_interrupts},
     structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    VirtAddr,
 };
 
-use crate::{assembly::registers::Registers, multitasking::TaskID, wrap_function_registers};
+use crate::{
+    assembly::registers::Registers,
+    memory::vma::VmaError,
+    multitasking::{
+        scheduler::Priority,
+        stack::{DEFAULT_STACK_SIZE, MAX_STACK_SIZE},
+        sync::SyncID,
+        timer::duration_to_ticks,
+        ExitStatus, ProcessID, TaskID, TaskInfo,
+    },
+    wrap_function_registers,
+};
 
 pub const SYSCALL_ADDR: usize = 0x80;
 const ECHO: usize = 0;
 const YIELD_NOW: usize = 1;
 const SPAWN_THREAD: usize = 2;
 const QUIT_FUNC: usize = 3;
+const SPAWN_PROCESS: usize = 4;
+const MMAP: usize = 5;
+const MUNMAP: usize = 6;
+const MPROTECT: usize = 7;
+const FORK: usize = 8;
+const SET_PRIORITY: usize = 9;
+const SLEEP: usize = 10;
+const JOIN: usize = 11;
+const DETACH: usize = 12;
+const KILL: usize = 13;
+const SYNC_CREATE: usize = 14;
+const SYNC_DESTROY: usize = 15;
+const MUTEX_LOCK: usize = 16;
+const MUTEX_UNLOCK: usize = 17;
+const SEMAPHORE_ACQUIRE: usize = 18;
+const SEMAPHORE_RELEASE: usize = 19;
+const CONDVAR_WAIT: usize = 20;
+const CONDVAR_NOTIFY: usize = 21;
+const TASK_LIST: usize = 22;
+const SET_NAME: usize = 23;
+
+// Returned by join when there is no thread it can wait for
+pub(crate) const JOIN_NOT_FOUND: usize = usize::MAX;
+// Returned to the none task, which can't block, by syscalls that would have blocked it
+pub(crate) const PENDING: usize = usize::MAX - 1;
+
+// Flags for mmap and mprotect
+pub const PROT_WRITE: usize = 1 << 0;
+// Map at exactly the address given instead of wherever there is room
+pub const MAP_FIXED: usize = 1 << 1;
+// Map every page straight away instead of when it's first touched
+pub const MAP_POPULATE: usize = 1 << 2;
+// Backed by a file, not supported yet
+pub const MAP_FILE: usize = 1 << 3;
+
+/// Errors returned by the memory syscalls
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryError {
+    // Not page aligned, empty or outside the process region
+    InvalidRange,
+    // Part of the range is already mapped
+    Overlaps,
+    // Part of the range isn't mapped
+    NotMapped,
+    OutOfMemory,
+    Unsupported,
+}
+
+impl MemoryError {
+    const ALL: [MemoryError; 5] = [
+        MemoryError::InvalidRange,
+        MemoryError::Overlaps,
+        MemoryError::NotMapped,
+        MemoryError::OutOfMemory,
+        MemoryError::Unsupported,
+    ];
+
+    // Errors are returned at the very top of the number range, where no address can be
+    pub(crate) fn code(self) -> usize {
+        usize::MAX - self as usize
+    }
+
+    fn from_code(code: usize) -> Option<Self> {
+        Self::ALL.iter().copied().find(|err| err.code() == code)
+    }
+}
+
+impl From<VmaError> for MemoryError {
+    fn from(err: VmaError) -> Self {
+        match err {
+            VmaError::NotAligned | VmaError::Empty | VmaError::OutOfRegion => {
+                MemoryError::InvalidRange
+            }
+            VmaError::Overlaps => MemoryError::Overlaps,
+            VmaError::NotFound => MemoryError::NotMapped,
+            VmaError::OutOfMemory => MemoryError::OutOfMemory,
+        }
+    }
+}
 
 pub fn set_syscall_idt(idt: &mut InterruptDescriptorTable) {
     idt[SYSCALL_ADDR].set_handler_fn(wrapped_syscall_handler);
@@ -32,6 +126,52 @@ extern "C" fn syscall_handler(stack_frame: &mut InterruptStackFrame, regs: &mut
         QUIT_FUNC => crate::multitasking::TASKMANAGER
             .lock()
             .quit(stack_frame, regs),
+        SPAWN_PROCESS => crate::multitasking::TASKMANAGER
+            .lock()
+            .spawn_process_sys(regs),
+        MMAP => crate::multitasking::TASKMANAGER.lock().mmap_sys(regs),
+        MUNMAP => crate::multitasking::TASKMANAGER.lock().munmap_sys(regs),
+        MPROTECT => crate::multitasking::TASKMANAGER.lock().mprotect_sys(regs),
+        FORK => crate::multitasking::TASKMANAGER.lock().fork_sys(regs),
+        SET_PRIORITY => crate::multitasking::TASKMANAGER
+            .lock()
+            .set_priority_sys(regs),
+        SLEEP => crate::multitasking::TASKMANAGER
+            .lock()
+            .sleep_sys(stack_frame, regs),
+        JOIN => crate::multitasking::TASKMANAGER
+            .lock()
+            .join_sys(stack_frame, regs),
+        DETACH => crate::multitasking::TASKMANAGER.lock().detach_sys(regs),
+        KILL => crate::multitasking::TASKMANAGER
+            .lock()
+            .kill_sys(stack_frame, regs),
+        SYNC_CREATE => crate::multitasking::TASKMANAGER
+            .lock()
+            .sync_create_sys(regs),
+        SYNC_DESTROY => crate::multitasking::TASKMANAGER
+            .lock()
+            .sync_destroy_sys(regs),
+        MUTEX_LOCK => crate::multitasking::TASKMANAGER
+            .lock()
+            .mutex_lock_sys(stack_frame, regs),
+        MUTEX_UNLOCK => crate::multitasking::TASKMANAGER
+            .lock()
+            .mutex_unlock_sys(regs),
+        SEMAPHORE_ACQUIRE => crate::multitasking::TASKMANAGER
+            .lock()
+            .semaphore_acquire_sys(stack_frame, regs),
+        SEMAPHORE_RELEASE => crate::multitasking::TASKMANAGER
+            .lock()
+            .semaphore_release_sys(regs),
+        CONDVAR_WAIT => crate::multitasking::TASKMANAGER
+            .lock()
+            .condvar_wait_sys(stack_frame, regs),
+        CONDVAR_NOTIFY => crate::multitasking::TASKMANAGER
+            .lock()
+            .condvar_notify_sys(regs),
+        TASK_LIST => crate::multitasking::TASKMANAGER.lock().task_list_sys(regs),
+        SET_NAME => crate::multitasking::TASKMANAGER.lock().set_name_sys(regs),
         _ => println!("Unknown syscall class: {}", regs.rax),
     })
 }
@@ -41,6 +181,23 @@ unsafe fn syscall1(mut syscall_number: usize, arg1: usize) -> usize {
     syscall_number
 }
 
+unsafe fn syscall2(mut syscall_number: usize, arg1: usize, arg2: usize) -> usize {
+    asm!("int 0x80", inout("rax") syscall_number, in("r8") arg1, in("r9") arg2, options(nostack));
+    syscall_number
+}
+
+unsafe fn syscall3(mut syscall_number: usize, arg1: usize, arg2: usize, arg3: usize) -> usize {
+    asm!(
+        "int 0x80",
+        inout("rax") syscall_number,
+        in("r8") arg1,
+        in("r9") arg2,
+        in("r10") arg3,
+        options(nostack)
+    );
+    syscall_number
+}
+
 /// Syscall test
 /// Will return number passed as arg1
 pub fn echo(number: usize) -> usize {
@@ -56,18 +213,279 @@ pub fn yield_now() {
     unsafe { syscall1(YIELD_NOW, 0) };
 }
 
-pub fn spawn_thread<F>(func: F) -> TaskID
+/// Stops running this thread for at least duration, letting everything else run
+/// Rounded up to whole timer ticks, returns straight away if called from the none task
+pub fn sleep(duration: Duration) {
+    unsafe { syscall1(SLEEP, duration_to_ticks(duration) as usize) };
+}
+
+/// Why a thread couldn't be joined
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    // It panicked before returning a value
+    Panicked,
+    // Another thread killed it
+    Killed,
+    // It was never created
+    NotFound,
+}
+
+/// Owns the right to wait for a thread and take what its function returned
+/// Dropping the handle detaches the thread
+pub struct JoinHandle<T> {
+    id: TaskID,
+    // Filled in by the thread when its function returns
+    result: Arc<Mutex<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The thread's id, the none task if it couldn't be created
+    pub fn id(&self) -> TaskID {
+        self.id
+    }
+
+    /// Waits for the thread to end and returns what its function returned
+    pub fn join(mut self) -> Result<T, JoinError> {
+        let status = retry_pending(|| unsafe { syscall1(JOIN, usize::from(self.id)) });
+        // The kernel has forgotten the thread, there is nothing left to detach
+        self.id = TaskID::none_task();
+
+        match ExitStatus::from_usize(status) {
+            Some(ExitStatus::Exited) => self.result.lock().take().ok_or(JoinError::Panicked),
+            Some(ExitStatus::Panicked) => Err(JoinError::Panicked),
+            Some(ExitStatus::Killed) => Err(JoinError::Killed),
+            None => Err(JoinError::NotFound),
+        }
+    }
+
+    /// Lets the thread run on its own, how it ends is thrown away
+    pub fn detach(self) {
+        drop(self)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        if !self.id.is_none() {
+            unsafe { syscall1(DETACH, usize::from(self.id)) };
+        }
+    }
+}
+
+// Makes a syscall again for as long as it returns PENDING
+pub(crate) fn retry_pending(mut syscall: impl FnMut() -> usize) -> usize {
+    loop {
+        let res = syscall();
+        if res != PENDING {
+            return res;
+        }
+        idle_wait();
+    }
+}
+
+// Lets every other ready thread run, then halts until the next interrupt
+// For the none task, which can't block, to wait for something another thread will do
+pub(crate) fn idle_wait() {
+    yield_now();
+
+    // Leave interrupts as they were
+    let enabled = interrupts::are_enabled();
+    enable_and_hlt();
+    if !enabled {
+        interrupts::disable();
+    }
+}
+
+pub fn spawn_thread<F, T>(func: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + Sync,
+    T: Send,
+{
+    spawn_thread_with_stack(DEFAULT_STACK_SIZE, func)
+}
+
+/// Spawns a thread with a stack of stack_size bytes (rounded up to whole pages)
+/// The handle's id is the none task if the thread could not be created
+pub fn spawn_thread_with_stack<F, T>(stack_size: usize, func: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + Sync,
+    T: Send,
+{
+    spawn_thread_with(stack_size, Priority::default(), func)
+}
+
+/// Spawns a thread that is scheduled at priority instead of Normal
+pub fn spawn_thread_with_priority<F, T>(priority: Priority, func: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + Sync,
+    T: Send,
+{
+    spawn_thread_with(DEFAULT_STACK_SIZE, priority, func)
+}
+
+/// Spawns a thread with a stack of stack_size bytes at priority
+/// The handle's id is the none task if the thread could not be created
+pub fn spawn_thread_with<F, T>(stack_size: usize, priority: Priority, func: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + Sync,
+    T: Send,
+{
+    assert!(
+        stack_size > 0 && stack_size <= MAX_STACK_SIZE,
+        "Stack size must be between 1 and {} bytes",
+        MAX_STACK_SIZE
+    );
+
+    let result = Arc::new(Mutex::new(None));
+    let thread_result = result.clone();
+    let boxed_func: Box<dyn FnOnce()> = Box::new(move || {
+        let value = func();
+        *thread_result.lock() = Some(value);
+    });
+    let raw = Box::into_raw(Box::new(boxed_func)) as *mut usize;
+    let id = TaskID::from(unsafe {
+        syscall3(SPAWN_THREAD, raw as usize, stack_size, priority as usize)
+    });
+
+    if id.is_none() {
+        // The thread never started so the function is still ours to drop
+        drop(unsafe { Box::from_raw(raw as *mut Box<dyn FnOnce()>) });
+    }
+    JoinHandle { id, result }
+}
+
+/// Runs func as the first thread of a new process with its own address space
+/// Returns the kernel process if the process could not be created
+pub fn spawn_process<F>(func: F) -> ProcessID
 where
     F: FnOnce() + Send + Sync,
 {
     let boxed_func: Box<dyn FnOnce()> = Box::new(func);
     let raw = Box::into_raw(Box::new(boxed_func)) as *mut usize;
-    let res = unsafe { syscall1(SPAWN_THREAD, raw as usize) };
-    TaskID::from(res)
+    let res = ProcessID::from(unsafe { syscall2(SPAWN_PROCESS, raw as usize, DEFAULT_STACK_SIZE) });
+
+    if res.is_kernel() {
+        // The process never started so the function is still ours to drop
+        drop(unsafe { Box::from_raw(raw as *mut Box<dyn FnOnce()>) });
+    }
+    res
+}
+
+/// Runs func as the first thread of a new process that starts with a copy of this process's memory
+/// Pages are shared until one side writes to them, then that side gets its own copy
+/// Unlike a Unix fork the caller doesn't return twice, the child runs func instead
+/// Returns the kernel process if the process could not be created
+pub fn fork<F>(func: F) -> ProcessID
+where
+    F: FnOnce() + Send + Sync,
+{
+    let boxed_func: Box<dyn FnOnce()> = Box::new(func);
+    let raw = Box::into_raw(Box::new(boxed_func)) as *mut usize;
+    let res = ProcessID::from(unsafe { syscall2(FORK, raw as usize, DEFAULT_STACK_SIZE) });
+
+    if res.is_kernel() {
+        // The process never started so the function is still ours to drop
+        drop(unsafe { Box::from_raw(raw as *mut Box<dyn FnOnce()>) });
+    }
+    res
+}
+
+/// Changes the priority of a thread, the none task changes the calling thread's
+/// Returns false if there is no such thread
+pub fn set_priority(task: TaskID, priority: Priority) -> bool {
+    unsafe { syscall2(SET_PRIORITY, usize::from(task), priority as usize) == 1 }
+}
+
+pub(crate) fn sync_create(kind: usize, permits: usize) -> SyncID {
+    SyncID::from(unsafe { syscall2(SYNC_CREATE, kind, permits) })
+}
+
+pub(crate) fn sync_destroy(id: SyncID) {
+    unsafe { syscall1(SYNC_DESTROY, usize::from(id)) };
+}
+
+pub(crate) fn mutex_lock(id: SyncID) -> usize {
+    unsafe { syscall1(MUTEX_LOCK, usize::from(id)) }
+}
+
+pub(crate) fn mutex_unlock(id: SyncID) -> usize {
+    unsafe { syscall1(MUTEX_UNLOCK, usize::from(id)) }
+}
+
+pub(crate) fn semaphore_acquire(id: SyncID) -> usize {
+    unsafe { syscall1(SEMAPHORE_ACQUIRE, usize::from(id)) }
+}
+
+pub(crate) fn semaphore_release(id: SyncID) -> usize {
+    unsafe { syscall1(SEMAPHORE_RELEASE, usize::from(id)) }
+}
+
+pub(crate) fn condvar_wait(condvar: SyncID, mutex: SyncID) -> usize {
+    unsafe { syscall2(CONDVAR_WAIT, usize::from(condvar), usize::from(mutex)) }
+}
+
+pub(crate) fn condvar_notify(id: SyncID, count: usize) -> usize {
+    unsafe { syscall2(CONDVAR_NOTIFY, usize::from(id), count) }
+}
+
+/// Every thread there is, what it's doing and how many ticks it has run for
+pub fn task_list() -> Vec<TaskInfo> {
+    let mut tasks = Vec::new();
+    loop {
+        let count = unsafe { syscall2(TASK_LIST, tasks.as_mut_ptr() as usize, tasks.capacity()) };
+        if count <= tasks.capacity() {
+            unsafe { tasks.set_len(count) };
+            return tasks;
+        }
+        // More threads may have been spawned by the time it's called again
+        tasks.reserve(count);
+    }
+}
+
+/// Names a thread for ps, the none task names the calling thread
+/// Returns false if there is no such thread or the name is empty
+pub fn set_thread_name(task: TaskID, name: &str) -> bool {
+    let (ptr, len) = (name.as_ptr() as usize, name.len());
+    unsafe { syscall3(SET_NAME, usize::from(task), ptr, len) == 1 }
+}
+
+/// Reserves len bytes of memory in this thread's process
+/// Without MAP_FIXED addr is ignored and the memory goes wherever there is room
+/// Pages are mapped when first touched unless MAP_POPULATE is set
+pub fn mmap(addr: Option<VirtAddr>, len: usize, flags: usize) -> Result<VirtAddr, MemoryError> {
+    let addr = addr.map_or(0, |addr| addr.as_u64() as usize);
+    let res = unsafe { syscall3(MMAP, addr, len, flags) };
+
+    match MemoryError::from_code(res) {
+        Some(err) => Err(err),
+        None => Ok(VirtAddr::new(res as u64)),
+    }
+}
+
+/// Unmaps len bytes at addr, parts of the range that aren't mapped are ignored
+pub fn munmap(addr: VirtAddr, len: usize) -> Result<(), MemoryError> {
+    let res = unsafe { syscall2(MUNMAP, addr.as_u64() as usize, len) };
+    MemoryError::from_code(res).map_or(Ok(()), Err)
+}
+
+/// Changes the protection of len bytes at addr, which must all be mapped
+pub fn mprotect(addr: VirtAddr, len: usize, flags: usize) -> Result<(), MemoryError> {
+    let res = unsafe { syscall3(MPROTECT, addr.as_u64() as usize, len, flags) };
+    MemoryError::from_code(res).map_or(Ok(()), Err)
+}
+
+/// Ends another thread wherever it is, whoever joins it gets JoinError::Killed
+/// Nothing it owned is dropped and spin locks it held stay locked, so only kill threads that can't be told to stop
+/// Sleeping mutexes it held are handed on, though what they protect may be left half changed
+/// Killing the calling thread ends it like exit_thread, the none task can't be killed
+/// Returns false if there is no such thread
+pub fn kill(task: TaskID) -> bool {
+    unsafe { syscall1(KILL, usize::from(task)) == 1 }
 }
 
-pub fn quit_function() -> ! {
-    unsafe { syscall1(QUIT_FUNC, 0) };
+/// Ends the calling thread, whoever joins it gets status
+pub fn exit_thread(status: ExitStatus) -> ! {
+    unsafe { syscall1(QUIT_FUNC, status as usize) };
 
     panic!("Function failed to QUIT")
 }
@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{
+        self,
+        vma::{self, VmaError},
+    },
+};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+// Unused by anything else in the kernel
+const LAZY_START: u64 = 0x5555_0000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn reserved_memory_is_mapped_when_touched() {
+    let start = VirtAddr::new(LAZY_START);
+    vma::reserve_kernel(start, 64 * 4096, PageTableFlags::WRITABLE).unwrap();
+    let before = memory::frame_allocator().stats().free;
+
+    // Touch two of the pages
+    let ptr = start.as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(42);
+        ptr.add(10 * 4096 / 8).write_volatile(7);
+        assert_eq!(ptr.read_volatile(), 42);
+        // Pages start out zeroed
+        assert_eq!(ptr.add(1).read_volatile(), 0);
+    }
+
+    // Two pages plus the page tables needed to reach them
+    let used = before - memory::frame_allocator().stats().free;
+    assert!(used >= 2 && used <= 5);
+
+    // Only the page tables stay behind
+    vma::release_kernel(start).unwrap();
+    assert_eq!(memory::frame_allocator().stats().free, before - (used - 2));
+}
+
+#[test_case]
+fn overlapping_reservations_fail() {
+    let start = VirtAddr::new(LAZY_START + 0x10_0000);
+    vma::reserve_kernel(start, 4 * 4096, PageTableFlags::WRITABLE).unwrap();
+
+    assert_eq!(
+        vma::reserve_kernel(start + 4096u64, 4096, PageTableFlags::WRITABLE),
+        Err(VmaError::Overlaps)
+    );
+
+    vma::release_kernel(start).unwrap();
+    assert_eq!(vma::release_kernel(start), Err(VmaError::NotFound));
+}
@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{
+        self,
+        dma::{DmaBuffer, DmaError},
+    },
+};
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn buffer_is_contiguous_and_below_4gib() {
+    let buffer = DmaBuffer::new(5 * 4096).unwrap();
+    let phys = buffer.phys_addr();
+    assert!(phys.is_aligned(4096u64));
+    assert!(phys.as_u64() + buffer.size() as u64 <= 0x1_0000_0000);
+
+    let mapper = memory::mapper();
+    for offset in (0..buffer.size() as u64).step_by(4096) {
+        let translated = mapper.translate_addr(buffer.virt_addr() + offset);
+        assert_eq!(translated, Some(phys + offset));
+    }
+}
+
+#[test_case]
+fn buffer_is_zeroed_and_shared_with_physical_memory() {
+    let mut buffer = DmaBuffer::new(100).unwrap();
+    assert_eq!(buffer.size(), 4096);
+    assert!(buffer.as_slice().iter().all(|byte| *byte == 0));
+
+    buffer.as_mut_slice()[10] = 0xAB;
+    let direct = memory::phys_to_virt(buffer.phys_addr() + 10u64);
+    assert_eq!(unsafe { *direct.as_ptr::<u8>() }, 0xAB);
+}
+
+#[test_case]
+fn drop_frees_frames() {
+    // Any page tables needed for the mapping stay around, so make them first
+    drop(DmaBuffer::new(8 * 4096).unwrap());
+
+    let used = memory::frame_allocator().stats().used;
+    let buffer = DmaBuffer::new(8 * 4096).unwrap();
+    let virt = buffer.virt_addr();
+    drop(buffer);
+
+    assert_eq!(memory::frame_allocator().stats().used, used);
+    assert_eq!(memory::mapper().translate_addr(virt), None);
+}
+
+#[test_case]
+fn empty_buffer_fails() {
+    assert!(matches!(DmaBuffer::new(0), Err(DmaError::Empty)));
+}
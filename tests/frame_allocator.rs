@@ -0,0 +1,103 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{hlt_loop, memory};
+use x86_64::{
+    structures::paging::{FrameAllocator, FrameDeallocator},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn freed_frame_is_reused() {
+    let mut frame_allocator = memory::frame_allocator();
+
+    let frame = frame_allocator.allocate_frame().unwrap();
+    unsafe { frame_allocator.deallocate_frame(frame) };
+
+    // The lowest free frame should be handed out again
+    assert_eq!(frame_allocator.allocate_frame(), Some(frame));
+    unsafe { frame_allocator.deallocate_frame(frame) };
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let mut frame_allocator = memory::frame_allocator();
+    let before = frame_allocator.stats();
+
+    let frame = frame_allocator.allocate_frame().unwrap();
+    assert_eq!(frame_allocator.stats().free, before.free - 1);
+    assert_eq!(frame_allocator.stats().used, before.used + 1);
+
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.stats().free, before.free);
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    let mut frame_allocator = memory::frame_allocator();
+    let before = frame_allocator.stats();
+
+    let range = frame_allocator.allocate_contiguous(16).unwrap();
+    assert_eq!(range.end - range.start, 16);
+    assert_eq!(frame_allocator.stats().free, before.free - 16);
+
+    unsafe { frame_allocator.deallocate_contiguous(range) };
+    assert_eq!(frame_allocator.stats().free, before.free);
+}
+
+#[test_case]
+fn shared_frame_needs_every_reference_freed() {
+    let mut frame_allocator = memory::frame_allocator();
+    let before = frame_allocator.stats();
+
+    let frame = frame_allocator.allocate_frame().unwrap();
+    assert!(frame_allocator.share_frame(frame));
+    assert_eq!(frame_allocator.references(frame), 2);
+    assert_eq!(frame_allocator.stats().shared, before.shared + 1);
+
+    // The first free only drops the extra reference
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.references(frame), 1);
+    assert_eq!(frame_allocator.stats().free, before.free - 1);
+
+    unsafe { frame_allocator.deallocate_frame(frame) };
+    assert_eq!(frame_allocator.references(frame), 0);
+    assert_eq!(frame_allocator.stats().free, before.free);
+}
+
+#[test_case]
+fn memory_map_summary_matches_stats() {
+    let frame_allocator = memory::frame_allocator();
+    let summary = frame_allocator.memory_map_summary();
+    let stats = frame_allocator.stats();
+
+    assert_eq!(summary.usable, stats.total as u64 * 4096);
+    assert_eq!(summary.free, stats.free as u64 * 4096);
+    // The kernel image always shows up in the map
+    assert!(summary.kernel > 0);
+}
@@ -11,9 +11,9 @@ use alloc::{boxed::Box, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use crafty_os::{
-    allocator::{self, HEAP_SIZE},
+    allocator::{self, slab::SlabCache, HEAP_SIZE},
     hlt_loop,
-    memory::{self, BootInfoFrameAllocator},
+    memory,
 };
 use x86_64::VirtAddr;
 
@@ -24,14 +24,13 @@ fn main(boot_info: &'static BootInfo) -> ! {
 
     let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
 
-    let mut mapper = unsafe { memory::init(physical_memory_offset) };
-
-    let mut frame_allocator = unsafe {
+    unsafe {
+        memory::init(physical_memory_offset);
         // Init the frame allocator
-        BootInfoFrameAllocator::init(&boot_info.memory_map)
+        memory::init_frame_allocator(&boot_info.memory_map)
     };
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
+    allocator::init_heap().expect("Heap initialization failed");
 
     test_main();
     hlt_loop();
@@ -72,3 +71,112 @@ fn many_boxes() {
         assert_eq!(*x, i)
     }
 }
+
+#[test_case]
+fn large_allocation_after_fragmentation() {
+    // Chop most of the heap up into small blocks then free them all
+    let boxes: Vec<Box<[u8; 64]>> = (0..HEAP_SIZE / 128).map(|_| Box::new([0u8; 64])).collect();
+    drop(boxes);
+
+    // The freed blocks have to be merged back together for this to fit
+    let vec = vec![1u8; HEAP_SIZE / 2];
+    assert_eq!(vec.len(), HEAP_SIZE / 2);
+}
+
+// Only the fixed block allocator can grow the heap
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn grow_beyond_initial_heap() {
+    // Allocate more than the initially mapped heap so that it has to grow
+    let vec = vec![1u8; HEAP_SIZE * 2];
+    assert_eq!(vec.iter().map(|&b| b as usize).sum::<usize>(), HEAP_SIZE * 2);
+    assert!(allocator::heap_size() > HEAP_SIZE);
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn size_class_stats_count_blocks() {
+    // 100 bytes goes in the 128 byte class
+    let index = allocator::fixed_size_block::BLOCK_SIZES
+        .iter()
+        .position(|&size| size == 128)
+        .unwrap();
+    let before = allocator::ALLOCATOR.lock().stats().size_classes[index];
+
+    let boxes: Vec<Box<[u8; 100]>> = (0..3).map(|_| Box::new([0u8; 100])).collect();
+    let during = allocator::ALLOCATOR.lock().stats().size_classes[index];
+    assert_eq!(during.block_size, 128);
+    assert_eq!(during.allocs, before.allocs + 3);
+    assert_eq!(during.live_blocks, before.live_blocks + 3);
+    assert_eq!(during.live_bytes(), before.live_bytes() + 3 * 128);
+
+    drop(boxes);
+    let after = allocator::ALLOCATOR.lock().stats().size_classes[index];
+    assert_eq!(after.frees, before.frees + 3);
+    assert_eq!(after.live_blocks, before.live_blocks);
+    // Freed blocks are kept for the next allocation of that size
+    assert!(after.cached_blocks >= 3);
+}
+
+#[cfg(feature = "alloc-fixed-block")]
+#[test_case]
+fn high_water_mark_stays_after_free() {
+    let before = allocator::ALLOCATOR.lock().stats();
+
+    // Too big for a size class
+    let vec = vec![1u8; HEAP_SIZE / 4];
+    let during = allocator::ALLOCATOR.lock().stats();
+    assert_eq!(during.large_live_blocks, before.large_live_blocks + 1);
+    assert_eq!(during.large_live_bytes, before.large_live_bytes + vec.len());
+    assert!(during.high_water_mark >= before.live_bytes + vec.len());
+
+    drop(vec);
+    let after = allocator::ALLOCATOR.lock().stats();
+    assert_eq!(after.large_live_blocks, before.large_live_blocks);
+    assert_eq!(after.live_bytes, before.live_bytes);
+    assert_eq!(after.high_water_mark, during.high_water_mark);
+}
+
+#[cfg(all(feature = "alloc-fixed-block", feature = "heap-debug"))]
+#[test_case]
+fn freed_blocks_are_poisoned() {
+    let ptr = Box::into_raw(Box::new([0u8; 100])) as *mut u8;
+    drop(unsafe { Box::from_raw(ptr as *mut [u8; 100]) });
+
+    // The start of the block holds the next free block, the rest of its 128 bytes is poison
+    let start = core::mem::size_of::<usize>();
+    for offset in start..128 {
+        assert_eq!(unsafe { core::ptr::read_volatile(ptr.add(offset)) }, 0xDE);
+    }
+}
+
+static TEST_CACHE: SlabCache<[u64; 4]> = SlabCache::new("test");
+
+#[test_case]
+fn slab_reuses_freed_objects() {
+    let object = TEST_CACHE.alloc([1, 2, 3, 4]);
+    assert_eq!(*object, [1, 2, 3, 4]);
+    let address = &*object as *const [u64; 4];
+    drop(object);
+
+    // The most recently freed object is handed out first
+    let object = TEST_CACHE.alloc([5, 6, 7, 8]);
+    assert_eq!(&*object as *const [u64; 4], address);
+    assert_eq!(*object, [5, 6, 7, 8]);
+}
+
+#[test_case]
+fn slab_stats_track_objects() {
+    let before = TEST_CACHE.stats();
+
+    // Enough objects to need more than one slab
+    let objects: Vec<_> = (0..before.objects_per_slab * 2)
+        .map(|i| TEST_CACHE.alloc([i as u64; 4]))
+        .collect();
+    let during = TEST_CACHE.stats();
+    assert_eq!(during.live_objects, before.live_objects + objects.len());
+    assert!(during.slabs >= 2);
+
+    drop(objects);
+    assert_eq!(TEST_CACHE.stats().live_objects, before.live_objects);
+}
@@ -0,0 +1,45 @@
+#![no_std]
+#![no_main]
+
+#[macro_use]
+extern crate crafty_os;
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc};
+use bootloader::{entry_point, BootInfo};
+use core::{alloc::Layout, panic::PanicInfo};
+use crafty_os::{
+    allocator, memory,
+    qemu::{exit_qemu, QemuExitCode},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+    allocator::init_heap().expect("Heap initialization failed");
+
+    serial_println!("Testing double free");
+    let layout = Layout::new::<[u8; 64]>();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+
+    serial_println!("[double free was not caught]");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
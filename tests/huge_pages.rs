@@ -0,0 +1,104 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{self, huge_page},
+};
+use x86_64::{
+    structures::paging::{
+        mapper::{MappedFrame, TranslateResult},
+        PageTableFlags, Size2MiB, Translate,
+    },
+    VirtAddr,
+};
+
+entry_point!(main);
+
+// Unused by anything else in the kernel, and 1 GiB aligned
+const HUGE_START: u64 = 0x5555_4000_0000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn huge_frames_are_aligned() {
+    let mut frame_allocator = memory::frame_allocator();
+    let frame = frame_allocator.allocate_huge::<Size2MiB>().unwrap();
+    assert!(frame.start_address().is_aligned(2 * 1024 * 1024u64));
+
+    let used = frame_allocator.stats().used;
+    unsafe { frame_allocator.deallocate_huge(frame) };
+    assert_eq!(frame_allocator.stats().used, used - 512);
+}
+
+#[test_case]
+fn aligned_region_gets_huge_pages() {
+    let start = VirtAddr::new(HUGE_START);
+    let size = 4 * 1024 * 1024;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let mut mapper = memory::mapper();
+    let mut frame_allocator = memory::frame_allocator();
+    let free = frame_allocator.stats().free;
+    huge_page::map_region(&mut mapper, &mut frame_allocator, start, size, flags).unwrap();
+
+    for offset in [0, size / 2, size - 8].iter() {
+        match mapper.translate(start + *offset) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {}
+            other => panic!("Expected a 2 MiB page, got {:?}", other),
+        }
+    }
+    // Only the page tables above the huge pages take frames beyond the memory itself
+    assert!(free - frame_allocator.stats().free <= 512 * 2 + 2);
+
+    unsafe { start.as_mut_ptr::<u64>().write_volatile(42) };
+    huge_page::unmap_region(&mut mapper, &mut frame_allocator, start, size);
+    assert!(free - frame_allocator.stats().free <= 2);
+}
+
+#[test_case]
+fn unaligned_region_falls_back_to_small_pages() {
+    let start = VirtAddr::new(HUGE_START + 4096);
+    let size = 2 * 1024 * 1024;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    let mut mapper = memory::mapper();
+    let mut frame_allocator = memory::frame_allocator();
+    huge_page::map_region(&mut mapper, &mut frame_allocator, start, size, flags).unwrap();
+
+    match mapper.translate(start) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(_),
+            ..
+        } => {}
+        other => panic!("Expected a 4 KiB page, got {:?}", other),
+    }
+
+    huge_page::unmap_region(&mut mapper, &mut frame_allocator, start, size);
+}
@@ -0,0 +1,108 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{
+        self,
+        address_space::{PROCESS_REGION_END, PROCESS_REGION_START},
+        layout::{self, Region},
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+const REGIONS: [Region; 4] = [Region::Heap, Region::Stacks, Region::Mmio, Region::Dma];
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn regions_dont_overlap() {
+    for (i, region) in REGIONS.iter().enumerate() {
+        let range = layout::region_range(*region);
+        assert!(range.start < range.end);
+        assert!(
+            range.end.as_u64() <= PROCESS_REGION_START
+                || range.start.as_u64() >= PROCESS_REGION_END
+        );
+
+        for other in REGIONS[i + 1..].iter() {
+            let other = layout::region_range(*other);
+            assert!(range.end <= other.start || other.end <= range.start);
+        }
+    }
+}
+
+#[test_case]
+fn heap_is_in_heap_region() {
+    let heap = VirtAddr::new(allocator::heap_start() as u64);
+    assert!(layout::region_range(Region::Heap).contains(&heap));
+    assert!(heap.is_aligned(2 * 1024 * 1024u64));
+}
+
+#[test_case]
+fn allocations_are_aligned_and_separate() {
+    let range = layout::region_range(Region::Mmio);
+    let first = layout::allocate(Region::Mmio, 0x3000, 4096).unwrap();
+    let second = layout::allocate(Region::Mmio, 0x1000, 0x10_0000).unwrap();
+
+    assert!(range.contains(&first) && range.contains(&second));
+    assert!(first.is_aligned(4096u64) && second.is_aligned(0x10_0000u64));
+    assert!(first + 0x3000u64 <= second || second + 0x1000u64 <= first);
+
+    layout::free(Region::Mmio, second, 0x1000);
+    layout::free(Region::Mmio, first, 0x3000);
+}
+
+#[test_case]
+fn freed_space_is_reused() {
+    let first = layout::allocate(Region::Dma, 0x4000, 4096).unwrap();
+    let _guard = layout::allocate(Region::Dma, 0x1000, 4096).unwrap();
+    layout::free(Region::Dma, first, 0x4000);
+
+    let again = layout::allocate(Region::Dma, 0x2000, 4096).unwrap();
+    assert_eq!(again, first);
+}
+
+#[test_case]
+fn adjacent_frees_are_merged() {
+    // Bigger than anything freed by the other tests, so both come from the end of the region
+    let first = layout::allocate(Region::Dma, 0x10_0000, 4096).unwrap();
+    let second = layout::allocate(Region::Dma, 0x10_0000, 4096).unwrap();
+    let _guard = layout::allocate(Region::Dma, 0x1000, 4096).unwrap();
+    assert_eq!(second, first + 0x10_0000u64);
+
+    layout::free(Region::Dma, first, 0x10_0000);
+    layout::free(Region::Dma, second, 0x10_0000);
+    let merged = layout::allocate(Region::Dma, 0x20_0000, 4096).unwrap();
+    assert_eq!(merged, first);
+}
+
+#[test_case]
+fn full_region_fails() {
+    assert!(layout::allocate(Region::Mmio, 1 << 40, 4096).is_none());
+}
@@ -0,0 +1,156 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{
+        self,
+        address_space::{PROCESS_REGION_END, PROCESS_REGION_START},
+    },
+    multitasking::TASKMANAGER,
+    syscall::{
+        fork, mmap, mprotect, munmap, MemoryError, MAP_FILE, MAP_FIXED, MAP_POPULATE, PROT_WRITE,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+    TASKMANAGER.lock().init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn mapped_memory_is_usable() {
+    let addr = mmap(None, 8 * 4096, PROT_WRITE).unwrap();
+
+    let ptr = addr.as_mut_ptr::<u64>();
+    unsafe {
+        ptr.write_volatile(1234);
+        ptr.add(7 * 4096 / 8).write_volatile(5678);
+        assert_eq!(ptr.read_volatile(), 1234);
+        assert_eq!(ptr.add(7 * 4096 / 8).read_volatile(), 5678);
+    }
+
+    munmap(addr, 8 * 4096).unwrap();
+}
+
+#[test_case]
+fn populate_maps_straight_away() {
+    let before = memory::frame_allocator().stats().free;
+    let addr = mmap(None, 16 * 4096, PROT_WRITE | MAP_POPULATE).unwrap();
+    assert!(before - memory::frame_allocator().stats().free >= 16);
+
+    munmap(addr, 16 * 4096).unwrap();
+}
+
+#[test_case]
+fn fixed_mappings_overlap() {
+    let addr = mmap(None, 4 * 4096, PROT_WRITE).unwrap();
+    assert_eq!(
+        mmap(Some(addr + 4096u64), 4096, PROT_WRITE | MAP_FIXED),
+        Err(MemoryError::Overlaps)
+    );
+
+    // Unmapping the middle leaves a hole that can be mapped again
+    munmap(addr + 4096u64, 4096).unwrap();
+    assert_eq!(
+        mmap(Some(addr + 4096u64), 4096, PROT_WRITE | MAP_FIXED),
+        Ok(addr + 4096u64)
+    );
+
+    munmap(addr, 4 * 4096).unwrap();
+}
+
+#[test_case]
+fn invalid_requests_fail() {
+    assert_eq!(mmap(None, 0, PROT_WRITE), Err(MemoryError::InvalidRange));
+    assert_eq!(mmap(None, 4096, MAP_FILE), Err(MemoryError::Unsupported));
+    assert_eq!(
+        mmap(Some(VirtAddr::new(0x1000)), 4096, MAP_FIXED),
+        Err(MemoryError::InvalidRange)
+    );
+
+    let addr = mmap(None, 4096, PROT_WRITE).unwrap();
+    assert_eq!(munmap(addr + 1u64, 4096), Err(MemoryError::InvalidRange));
+    // Only the first page is mapped
+    assert_eq!(mprotect(addr, 2 * 4096, 0), Err(MemoryError::NotMapped));
+    assert_eq!(mprotect(addr, 4096, 0), Ok(()));
+
+    munmap(addr, 4096).unwrap();
+}
+
+#[test_case]
+fn huge_lengths_fail() {
+    let region_size = (PROCESS_REGION_END - PROCESS_REGION_START) as usize;
+    let start = VirtAddr::new(PROCESS_REGION_START);
+
+    for &len in [usize::MAX, region_size + 1].iter() {
+        assert_eq!(mmap(None, len, PROT_WRITE), Err(MemoryError::InvalidRange));
+        assert_eq!(
+            mmap(Some(start), len, PROT_WRITE | MAP_FIXED),
+            Err(MemoryError::InvalidRange)
+        );
+        assert_eq!(munmap(start, len), Err(MemoryError::InvalidRange));
+        assert_eq!(mprotect(start, len, 0), Err(MemoryError::InvalidRange));
+    }
+
+    // Fits in the region but runs one page past its end
+    let addr = mmap(None, 4096, PROT_WRITE).unwrap();
+    let past_end = (PROCESS_REGION_END - addr.as_u64()) as usize + 4096;
+    assert_eq!(
+        mmap(Some(addr), past_end, PROT_WRITE | MAP_FIXED),
+        Err(MemoryError::InvalidRange)
+    );
+    assert_eq!(munmap(addr, past_end), Err(MemoryError::InvalidRange));
+    assert_eq!(mprotect(addr, past_end, 0), Err(MemoryError::InvalidRange));
+    // Nothing was changed by the failed calls
+    assert_eq!(mprotect(addr, 4096, PROT_WRITE), Ok(()));
+
+    munmap(addr, 4096).unwrap();
+}
+
+#[test_case]
+fn fork_shares_memory_until_written() {
+    let addr = mmap(None, 4096, PROT_WRITE | MAP_POPULATE).unwrap();
+    let ptr = addr.as_mut_ptr::<u64>();
+    unsafe { ptr.write_volatile(1234) };
+
+    let shared = memory::frame_allocator().stats().shared;
+    // The child is never scheduled, it only keeps its copy of the memory alive
+    let child = fork(|| {});
+    assert!(!child.is_kernel());
+    let forked = memory::frame_allocator().stats().shared;
+    assert!(forked > shared);
+
+    // Writing gives us our own copy of the page
+    unsafe {
+        ptr.write_volatile(5678);
+        assert_eq!(ptr.read_volatile(), 5678);
+    }
+    assert_eq!(memory::frame_allocator().stats().shared, forked - 1);
+
+    munmap(addr, 4096).unwrap();
+}
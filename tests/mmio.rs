@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{
+        self,
+        layout::{self, Region},
+        mmio::{map_mmio, MmioError},
+    },
+};
+use x86_64::{
+    structures::paging::{
+        mapper::{Translate, TranslateResult},
+        PageTableFlags,
+    },
+    PhysAddr, VirtAddr,
+};
+
+entry_point!(main);
+
+// The VGA text buffer, device memory that is always there
+const VGA_BUFFER: u64 = 0xb8000;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn mapping_is_uncached() {
+    let mmio = map_mmio(PhysAddr::new(VGA_BUFFER), 4096).unwrap();
+    assert!(layout::region_range(Region::Mmio).contains(&mmio.virt_addr()));
+
+    match memory::mapper().translate(mmio.virt_addr()) {
+        TranslateResult::Mapped { frame, flags, .. } => {
+            assert_eq!(frame.start_address().as_u64(), VGA_BUFFER);
+            assert!(flags.contains(PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH));
+            assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+        }
+        _ => panic!("MMIO range isn't mapped"),
+    }
+}
+
+#[test_case]
+fn unaligned_range_starts_at_offset() {
+    // The last row of the screen
+    let offset = 24 * 80 * 2;
+    let mut mmio = map_mmio(PhysAddr::new(VGA_BUFFER + offset), 160).unwrap();
+    assert_eq!(mmio.virt_addr().as_u64() % 4096, offset % 4096);
+
+    let old = mmio.read::<u16>(0);
+    mmio.write::<u16>(0, 0x0f41);
+    let direct = memory::phys_to_virt(PhysAddr::new(VGA_BUFFER + offset));
+    assert_eq!(unsafe { *direct.as_ptr::<u16>() }, 0x0f41);
+    mmio.write(0, old);
+}
+
+#[test_case]
+fn drop_unmaps() {
+    let mmio = map_mmio(PhysAddr::new(VGA_BUFFER), 4096).unwrap();
+    let virt = mmio.virt_addr();
+    drop(mmio);
+
+    assert!(matches!(
+        memory::mapper().translate(virt),
+        TranslateResult::NotMapped
+    ));
+}
+
+#[test_case]
+fn empty_range_fails() {
+    assert!(matches!(
+        map_mmio(PhysAddr::new(VGA_BUFFER), 0),
+        Err(MmioError::Empty)
+    ));
+}
@@ -0,0 +1,98 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{self, protection},
+    multitasking::stack::Stack,
+};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{mapper::TranslateResult, PageTableFlags, Translate},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    match memory::mapper().translate(addr) {
+        TranslateResult::Mapped { flags, .. } => flags,
+        _ => panic!("{:?} isn't mapped", addr),
+    }
+}
+
+#[test_case]
+fn protection_is_enabled() {
+    assert!(Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE));
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+}
+
+#[test_case]
+fn kernel_code_is_read_only() {
+    let flags = flags_of(VirtAddr::new(main as usize as u64));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(!flags.contains(PageTableFlags::NO_EXECUTE));
+    assert!(protection::kernel_code().contains(&VirtAddr::new(main as usize as u64)));
+}
+
+#[test_case]
+fn read_only_data_is_not_executable() {
+    // Never written to, so it ends up in .rodata
+    static TABLE: [u64; 4] = [1, 2, 3, 4];
+
+    let addr = VirtAddr::from_ptr(&TABLE);
+    assert!(protection::kernel_read_only().contains(&addr));
+    assert!(!protection::kernel_code().contains(&addr));
+    let flags = flags_of(addr);
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn heap_is_not_executable() {
+    let value = Box::new(42u64);
+    let flags = flags_of(VirtAddr::from_ptr(&*value));
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+}
+
+#[test_case]
+fn thread_stack_is_not_executable() {
+    // Well away from the task manager's stack slots
+    let stack = Stack::new(VirtAddr::new(0x5555_0000_0000), 4096).unwrap();
+    let flags = flags_of(stack.top() - 8u64);
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    assert!(flags.contains(PageTableFlags::NO_EXECUTE));
+    unsafe { stack.free() };
+}
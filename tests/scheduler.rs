@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::{
+    hint::spin_loop,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+use crafty_os::{
+    allocator, hlt_loop, memory,
+    multitasking::{
+        scheduler::{Priority, AGING_TICKS},
+        timer, TaskID, TASKMANAGER,
+    },
+    syscall::{kill, set_priority, sleep, spawn_thread, spawn_thread_with_priority, yield_now},
+};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::{self, enable_and_hlt, without_interrupts},
+    VirtAddr,
+};
+
+entry_point!(main);
+
+// Which threads ran, in order
+static LOG: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+    TASKMANAGER.lock().init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+// The test runs as the none task, which only runs again once no other thread is ready
+// Waits for the timer to wake sleeping threads until count threads have logged
+fn run_threads(count: usize) -> Vec<usize> {
+    yield_now();
+    // A thread may want the log as soon as the timer goes off
+    while without_interrupts(|| LOG.lock().len()) < count {
+        enable_and_hlt();
+    }
+    interrupts::disable();
+
+    core::mem::take(&mut *LOG.lock())
+}
+
+#[test_case]
+fn highest_priority_runs_first() {
+    spawn_thread_with_priority(Priority::Low, || LOG.lock().push(0));
+    spawn_thread_with_priority(Priority::Normal, || LOG.lock().push(1));
+    spawn_thread_with_priority(Priority::High, || LOG.lock().push(2));
+
+    assert_eq!(run_threads(3), [2, 1, 0]);
+}
+
+#[test_case]
+fn same_priority_runs_in_order() {
+    spawn_thread(|| LOG.lock().push(0));
+    spawn_thread(|| LOG.lock().push(1));
+
+    assert_eq!(run_threads(2), [0, 1]);
+}
+
+#[test_case]
+fn set_priority_moves_queued_thread() {
+    spawn_thread(|| LOG.lock().push(0));
+    let second = spawn_thread(|| LOG.lock().push(1));
+    assert!(set_priority(second.id(), Priority::High));
+
+    assert_eq!(run_threads(2), [1, 0]);
+}
+
+#[test_case]
+fn missing_thread_keeps_no_priority() {
+    assert!(!set_priority(TaskID::from(usize::MAX), Priority::High));
+    // The test is the none task, which always stays at the bottom
+    assert!(!set_priority(TaskID::none_task(), Priority::High));
+}
+
+#[test_case]
+fn waiting_thread_ages_past_busy_thread() {
+    static LOW_RAN: AtomicBool = AtomicBool::new(false);
+    static WAITED: AtomicU64 = AtomicU64::new(0);
+
+    let start = timer::ticks();
+    spawn_thread_with_priority(Priority::High, move || {
+        // Gives up eventually so a broken scheduler fails the test instead of hanging it
+        while !LOW_RAN.load(Ordering::SeqCst) && timer::ticks() < start + 10 * AGING_TICKS {
+            spin_loop();
+        }
+        LOG.lock().push(1);
+    });
+    spawn_thread_with_priority(Priority::Low, move || {
+        WAITED.store(timer::ticks() - start, Ordering::SeqCst);
+        LOW_RAN.store(true, Ordering::SeqCst);
+        LOG.lock().push(0);
+    });
+
+    assert_eq!(run_threads(2), [0, 1]);
+    // It moves up one queue every AGING_TICKS, two to reach High
+    let waited = WAITED.load(Ordering::SeqCst);
+    assert!(waited >= AGING_TICKS);
+    assert!(waited <= 2 * AGING_TICKS + 2);
+}
+
+#[test_case]
+fn sleeping_thread_lets_others_run() {
+    spawn_thread(|| {
+        sleep(Duration::from_millis(50));
+        LOG.lock().push(0);
+    });
+    spawn_thread(|| LOG.lock().push(1));
+
+    assert_eq!(run_threads(2), [1, 0]);
+}
+
+#[test_case]
+fn sleep_lasts_long_enough() {
+    static SLEPT: AtomicU64 = AtomicU64::new(0);
+
+    spawn_thread(|| {
+        let start = timer::ticks();
+        sleep(Duration::from_millis(100));
+        SLEPT.store(timer::ticks() - start, Ordering::SeqCst);
+        LOG.lock().push(0);
+    });
+
+    run_threads(1);
+    assert!(SLEPT.load(Ordering::SeqCst) >= timer::duration_to_ticks(Duration::from_millis(100)));
+}
+
+#[test_case]
+fn durations_round_up_to_ticks() {
+    assert_eq!(timer::duration_to_ticks(Duration::from_secs(0)), 0);
+    assert_eq!(timer::duration_to_ticks(Duration::from_nanos(1)), 1);
+    assert_eq!(timer::duration_to_ticks(Duration::from_secs(2)), 200);
+    assert_eq!(timer::duration_to_ticks(Duration::MAX), u64::MAX);
+}
+
+#[test_case]
+fn longest_sleep_doesnt_overflow() {
+    let sleeper = spawn_thread(|| sleep(Duration::MAX));
+    // Let it go to sleep
+    yield_now();
+    assert!(kill(sleeper.id()));
+}
@@ -0,0 +1,180 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use crafty_os::{
+    allocator, hlt_loop,
+    memory::{
+        self,
+        swap::{self, SwapError},
+    },
+    multitasking::TASKMANAGER,
+    syscall::{fork, mmap, munmap, spawn_thread, yield_now, MAP_POPULATE, PROT_WRITE},
+};
+use x86_64::{structures::paging::Translate, VirtAddr};
+
+entry_point!(main);
+
+// The test args attach a scratch disk as ATA 0 Slave (Disk 1), writes to it are thrown away
+const SWAP_DRIVE: u8 = 1;
+const SWAP_PAGES: usize = 64;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+    TASKMANAGER.lock().init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+// Swap can't be turned off again, so these run before it's enabled
+#[test_case]
+fn swap_starts_disabled() {
+    assert!(!swap::enabled());
+    assert!(swap::stats().is_none());
+}
+
+#[test_case]
+fn unknown_drive_is_rejected() {
+    assert_eq!(swap::init(4, 0, 16), Err(SwapError::NoDrive));
+}
+
+#[test_case]
+fn area_must_fit_on_the_drive() {
+    assert_eq!(swap::init(0, 0, 0), Err(SwapError::OutOfRange));
+    assert_eq!(swap::init(0, (1 << 28) - 8, 2), Err(SwapError::OutOfRange));
+    assert!(!swap::enabled());
+}
+
+#[test_case]
+fn swap_enables_on_spare_disk() {
+    assert_eq!(swap::init(SWAP_DRIVE, 0, SWAP_PAGES), Ok(()));
+    assert_eq!(
+        swap::init(SWAP_DRIVE, 0, SWAP_PAGES),
+        Err(SwapError::AlreadyEnabled)
+    );
+
+    let stats = swap::stats().unwrap();
+    assert_eq!(stats.drive, SWAP_DRIVE);
+    assert_eq!(stats.total, SWAP_PAGES);
+    assert_eq!(stats.used, 0);
+}
+
+fn swap_out(count: usize) -> usize {
+    TASKMANAGER.lock().swap_out(count)
+}
+
+fn is_mapped(addr: VirtAddr) -> bool {
+    memory::mapper().translate_addr(addr).is_some()
+}
+
+// Fills every word of each page with something different
+fn fill(addr: VirtAddr, pages: usize) {
+    let ptr = addr.as_mut_ptr::<u64>();
+    for i in 0..pages * 512 {
+        unsafe { ptr.add(i).write_volatile(i as u64 * 3 + 1) };
+    }
+}
+
+fn check(addr: VirtAddr, pages: usize) {
+    let ptr = addr.as_ptr::<u64>();
+    for i in 0..pages * 512 {
+        assert_eq!(unsafe { ptr.add(i).read_volatile() }, i as u64 * 3 + 1);
+    }
+}
+
+#[test_case]
+fn pages_round_trip_through_swap() {
+    let addr = mmap(None, 4 * 4096, PROT_WRITE | MAP_POPULATE).unwrap();
+    fill(addr, 4);
+
+    let before = swap::stats().unwrap();
+    let free = memory::frame_allocator().stats().free;
+    assert_eq!(swap_out(4), 4);
+    let swapped = swap::stats().unwrap();
+    assert_eq!(swapped.used, before.used + 4);
+    assert_eq!(swapped.swapped_out, before.swapped_out + 4);
+    assert_eq!(memory::frame_allocator().stats().free, free + 4);
+    assert!(!is_mapped(addr));
+
+    // Touching the pages reads them back in and frees their slots
+    check(addr, 4);
+    let after = swap::stats().unwrap();
+    assert_eq!(after.used, before.used);
+    assert_eq!(after.swapped_in, before.swapped_in + 4);
+
+    munmap(addr, 4 * 4096).unwrap();
+}
+
+#[test_case]
+fn clock_skips_recently_used_pages() {
+    let addr = mmap(None, 3 * 4096, PROT_WRITE | MAP_POPULATE).unwrap();
+    fill(addr, 3);
+
+    // Every page was used, so the first pass clears them all and the second takes one
+    assert_eq!(swap_out(1), 1);
+    let pages = [addr, addr + 4096u64, addr + 2 * 4096u64];
+    let mut left = pages.iter().filter(|&&page| is_mapped(page));
+    let (&used, &cold) = (left.next().unwrap(), left.next().unwrap());
+
+    // Only the page that wasn't touched since can be picked
+    unsafe { used.as_mut_ptr::<u64>().write_volatile(1) };
+    assert_eq!(swap_out(1), 1);
+    assert!(is_mapped(used));
+    assert!(!is_mapped(cold));
+
+    munmap(addr, 3 * 4096).unwrap();
+}
+
+#[test_case]
+fn munmap_frees_slots() {
+    let used = swap::stats().unwrap().used;
+    let addr = mmap(None, 8 * 4096, PROT_WRITE | MAP_POPULATE).unwrap();
+    fill(addr, 8);
+
+    assert_eq!(swap_out(8), 8);
+    assert_eq!(swap::stats().unwrap().used, used + 8);
+    munmap(addr, 8 * 4096).unwrap();
+    assert_eq!(swap::stats().unwrap().used, used);
+}
+
+#[test_case]
+fn fork_copies_swapped_pages() {
+    let used = swap::stats().unwrap().used;
+    let addr = mmap(None, 4096, PROT_WRITE | MAP_POPULATE).unwrap();
+    fill(addr, 1);
+    assert_eq!(swap_out(1), 1);
+
+    // The child gets its own slot holding the same page
+    let child = fork(|| {});
+    assert!(!child.is_kernel());
+    assert_eq!(swap::stats().unwrap().used, used + 2);
+
+    // Reading ours back leaves the child's copy in swap
+    check(addr, 1);
+    assert_eq!(swap::stats().unwrap().used, used + 1);
+
+    // The child's process is freed once its thread has quit and the next thread is spawned
+    yield_now();
+    spawn_thread(|| ()).join().unwrap();
+    assert_eq!(swap::stats().unwrap().used, used);
+
+    munmap(addr, 4096).unwrap();
+}
@@ -0,0 +1,158 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use crafty_os::{
+    allocator, hlt_loop, memory,
+    multitasking::{
+        sync::{Condvar, Mutex, Semaphore},
+        TASKMANAGER,
+    },
+    syscall::{kill, sleep, spawn_thread, yield_now},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+    TASKMANAGER.lock().init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn mutex_keeps_count_across_threads() {
+    let count = Arc::new(Mutex::new(0));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let count = count.clone();
+            spawn_thread(move || {
+                for _ in 0..50 {
+                    let mut guard = count.lock();
+                    let value = *guard;
+                    // Let the others find it locked
+                    yield_now();
+                    *guard = value + 1;
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*count.lock(), 200);
+}
+
+#[test_case]
+fn mutex_is_handed_over_in_order() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let guard = log.lock();
+
+    let handles: Vec<_> = (0..3)
+        .map(|index| {
+            let log = log.clone();
+            spawn_thread(move || log.lock().push(index))
+        })
+        .collect();
+    // They all block on the mutex in the order they were spawned
+    yield_now();
+    drop(guard);
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*log.lock(), [0, 1, 2]);
+}
+
+#[test_case]
+fn semaphore_limits_holders() {
+    static HOLDING: AtomicUsize = AtomicUsize::new(0);
+    static MOST: AtomicUsize = AtomicUsize::new(0);
+
+    let semaphore = Arc::new(Semaphore::new(2));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let semaphore = semaphore.clone();
+            spawn_thread(move || {
+                semaphore.acquire();
+                let holding = HOLDING.fetch_add(1, Ordering::SeqCst) + 1;
+                MOST.fetch_max(holding, Ordering::SeqCst);
+                sleep(Duration::from_millis(20));
+                HOLDING.fetch_sub(1, Ordering::SeqCst);
+                semaphore.release();
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(MOST.load(Ordering::SeqCst), 2);
+}
+
+#[test_case]
+fn condvar_wakes_waiters() {
+    let state = Arc::new((Mutex::new(false), Condvar::new()));
+    let handles: Vec<_> = (0..3)
+        .map(|_| {
+            let state = state.clone();
+            spawn_thread(move || {
+                let (ready, condvar) = &*state;
+                let guard = condvar.wait_while(ready.lock(), |ready| !*ready);
+                assert!(*guard);
+            })
+        })
+        .collect();
+    // Let them all start waiting
+    yield_now();
+
+    *state.0.lock() = true;
+    state.1.notify_all();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test_case]
+fn killed_owner_hands_mutex_on() {
+    let mutex = Arc::new(Mutex::new(0));
+    let owner = {
+        let mutex = mutex.clone();
+        spawn_thread(move || {
+            let _guard = mutex.lock();
+            sleep(Duration::from_secs(60));
+        })
+    };
+    yield_now();
+
+    assert!(kill(owner.id()));
+    *mutex.lock() += 1;
+    assert_eq!(*mutex.lock(), 1);
+}
@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    ptr::read_volatile,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use crafty_os::{
+    allocator, hlt_loop, memory,
+    multitasking::TASKMANAGER,
+    syscall::{spawn_thread, JoinError},
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+    TASKMANAGER.lock().init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    crafty_os::test::panic_handler(info)
+}
+
+// Recurses until it runs into the guard page below the thread's stack
+#[allow(unconditional_recursion)]
+fn overflow(depth: usize) -> usize {
+    let frame = [depth as u8; 256];
+    // Keeps the frame from being optimised away
+    overflow(depth + 1) + unsafe { read_volatile(&frame[0]) } as usize
+}
+
+#[test_case]
+fn overflow_only_ends_that_thread() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let overflowing = spawn_thread(|| overflow(0));
+    let other = spawn_thread(|| RAN.store(true, Ordering::SeqCst));
+
+    assert_eq!(overflowing.join(), Err(JoinError::Panicked));
+    assert_eq!(other.join(), Ok(()));
+    assert!(RAN.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn threads_still_run_after_overflow() {
+    spawn_thread(|| overflow(0)).join().ok();
+    assert_eq!(spawn_thread(|| 1 + 1).join(), Ok(2));
+}
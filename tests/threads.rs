@@ -0,0 +1,198 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(crafty_os::test::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::{
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use crafty_os::{
+    allocator, hlt_loop, memory,
+    multitasking::{self, ExitStatus, TaskID, TaskState, TASKMANAGER},
+    syscall::{
+        exit_thread, fork, kill, set_thread_name, sleep, spawn_process, spawn_thread, task_list,
+        yield_now, JoinError,
+    },
+};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    crafty_os::init();
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    unsafe {
+        memory::init(physical_memory_offset);
+        memory::init_frame_allocator(&boot_info.memory_map);
+    }
+
+    allocator::init_heap().expect("Heap initialization failed");
+    TASKMANAGER.lock().init();
+
+    test_main();
+    hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    // Threads are meant to panic in these tests, only the test itself panicking fails
+    if multitasking::panicking_thread().is_some() {
+        exit_thread(ExitStatus::Panicked);
+    }
+    crafty_os::test::panic_handler(info)
+}
+
+#[test_case]
+fn join_returns_value() {
+    assert_eq!(spawn_thread(|| 6 * 7).join(), Ok(42));
+}
+
+#[test_case]
+fn join_after_thread_ended() {
+    let handle = spawn_thread(|| 1);
+    // Nothing else is ready so the thread runs to the end
+    yield_now();
+    assert_eq!(handle.join(), Ok(1));
+}
+
+#[test_case]
+fn join_waits_for_sleeping_thread() {
+    let handle = spawn_thread(|| {
+        sleep(Duration::from_millis(30));
+        2
+    });
+    assert_eq!(handle.join(), Ok(2));
+}
+
+#[test_case]
+fn thread_blocks_joining_thread() {
+    let outer = spawn_thread(|| {
+        let inner = spawn_thread(|| {
+            sleep(Duration::from_millis(30));
+            3
+        });
+        inner.join().map(|value| value + 1)
+    });
+    assert_eq!(outer.join(), Ok(Ok(4)));
+}
+
+#[test_case]
+fn panicked_thread_reports_panic() {
+    let handle = spawn_thread(|| -> u32 { panic!("Thread panicked on purpose") });
+    assert_eq!(handle.join(), Err(JoinError::Panicked));
+}
+
+#[test_case]
+fn overflowed_thread_reports_panic() {
+    // Recurses until it runs into the guard page below the thread's stack
+    #[allow(unconditional_recursion)]
+    fn overflow(depth: usize) -> usize {
+        let frame = [depth as u8; 256];
+        overflow(depth + 1) + unsafe { core::ptr::read_volatile(&frame[0]) } as usize
+    }
+
+    assert_eq!(
+        spawn_thread(|| overflow(0)).join(),
+        Err(JoinError::Panicked)
+    );
+}
+
+#[test_case]
+fn quit_threads_give_stacks_back() {
+    // Where a local variable is, so somewhere in the thread's stack
+    fn stack_addr() -> usize {
+        let local = 0u8;
+        &local as *const u8 as usize
+    }
+
+    // The last thread's stack is only freed once the next one quits, so start with one there already
+    let first = spawn_thread(stack_addr).join().unwrap();
+    let free = memory::frame_allocator().stats().free;
+
+    for _ in 0..8 {
+        // Each thread gets the slot the one before it quit from
+        assert_eq!(spawn_thread(stack_addr).join(), Ok(first));
+    }
+    assert_eq!(memory::frame_allocator().stats().free, free);
+}
+
+#[test_case]
+fn detached_thread_still_runs() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    spawn_thread(|| RAN.store(true, Ordering::SeqCst)).detach();
+    yield_now();
+    assert!(RAN.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn ended_processes_arent_kept_for_joining() {
+    let unjoined = TASKMANAGER.lock().unjoined_count();
+
+    for _ in 0..4 {
+        assert!(!spawn_process(|| ()).is_kernel());
+        assert!(!fork(|| ()).is_kernel());
+    }
+    // Let them all run to the end
+    yield_now();
+    assert_eq!(TASKMANAGER.lock().unjoined_count(), unjoined);
+}
+
+#[test_case]
+fn kill_ready_thread() {
+    static RAN: AtomicBool = AtomicBool::new(false);
+
+    let handle = spawn_thread(|| RAN.store(true, Ordering::SeqCst));
+    assert!(kill(handle.id()));
+    assert_eq!(handle.join(), Err(JoinError::Killed));
+    assert!(!RAN.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn kill_wakes_joiner() {
+    let sleeper = spawn_thread(|| sleep(Duration::from_secs(60)));
+    let sleeper_id = sleeper.id();
+    let joiner = spawn_thread(move || sleeper.join());
+    // Let the sleeper go to sleep and the joiner block on it
+    yield_now();
+
+    assert!(kill(sleeper_id));
+    assert_eq!(joiner.join(), Ok(Err(JoinError::Killed)));
+}
+
+#[test_case]
+fn idle_task_cant_be_killed() {
+    assert!(!kill(TaskID::none_task()));
+    assert!(!kill(TaskID::from(usize::MAX)));
+}
+
+#[test_case]
+fn task_list_shows_state_and_name() {
+    let sleeper = spawn_thread(|| sleep(Duration::from_secs(60)));
+    let sleeper_id = sleeper.id();
+    assert!(set_thread_name(sleeper_id, "sleeper"));
+    let joiner = spawn_thread(move || sleeper.join());
+    // Let the sleeper go to sleep and the joiner block on it
+    yield_now();
+    let ready = spawn_thread(|| ());
+
+    let tasks = task_list();
+    let find = |id| tasks.iter().find(|task| task.id == id).unwrap();
+    // The test runs as the idle task
+    assert_eq!(find(TaskID::none_task()).state, TaskState::Running);
+    assert_eq!(find(TaskID::none_task()).name(), "idle");
+    assert_eq!(find(sleeper_id).state, TaskState::Sleeping);
+    assert_eq!(find(sleeper_id).name(), "sleeper");
+    assert_eq!(find(joiner.id()).state, TaskState::Blocked);
+    assert_eq!(find(ready.id()).state, TaskState::Ready);
+    assert_eq!(find(ready.id()).name(), "");
+
+    assert!(kill(sleeper_id));
+    assert_eq!(joiner.join(), Ok(Err(JoinError::Killed)));
+    assert_eq!(ready.join(), Ok(()));
+}